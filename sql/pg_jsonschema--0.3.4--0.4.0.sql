@@ -0,0 +1,328 @@
+-- Upgrades pg_jsonschema from 0.3.4 to 0.4.0.
+--
+-- The jsonschema type, its casts and its = and <> operators and hash operator
+-- class exist since 0.3.4, as do the functions not created here. Values stored
+-- by 0.3.4 are read as they are; they don't need to be rewritten.
+
+-- Structured validation errors
+
+CREATE FUNCTION jsonschema_validation_error_details(schema json, instance json)
+RETURNS jsonb[]
+IMMUTABLE STRICT PARALLEL SAFE
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'jsonschema_validation_error_details_wrapper';
+
+CREATE FUNCTION json_validation_error_details_compiled(schema jsonschema, instance json)
+RETURNS jsonb[]
+IMMUTABLE STRICT PARALLEL SAFE
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'json_validation_error_details_compiled_wrapper';
+
+CREATE FUNCTION jsonb_validation_error_details_compiled(schema jsonschema, instance jsonb)
+RETURNS jsonb[]
+IMMUTABLE STRICT PARALLEL SAFE
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'jsonb_validation_error_details_compiled_wrapper';
+
+CREATE FUNCTION jsonschema_validation_report(schema jsonschema, instance jsonb)
+RETURNS TABLE (instance_path text, schema_path text, keyword text, message text)
+IMMUTABLE STRICT PARALLEL SAFE
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'jsonschema_validation_report_wrapper';
+
+CREATE FUNCTION jsonschema_evaluate(schema jsonschema, instance jsonb, format text DEFAULT 'basic')
+RETURNS jsonb
+IMMUTABLE STRICT PARALLEL SAFE
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'jsonschema_evaluate_wrapper';
+
+CREATE FUNCTION jsonb_assert_matches_schema(schema jsonschema, instance jsonb)
+RETURNS jsonb
+IMMUTABLE STRICT PARALLEL SAFE
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'jsonb_assert_matches_schema_wrapper';
+
+-- Drafts
+
+CREATE FUNCTION jsonschema_from_json(schema json, draft text)
+RETURNS jsonschema
+IMMUTABLE STRICT PARALLEL SAFE
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'jsonschema_from_json_with_draft_wrapper';
+
+CREATE FUNCTION jsonschema_from_jsonb(schema jsonb, draft text)
+RETURNS jsonschema
+IMMUTABLE STRICT PARALLEL SAFE
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'jsonschema_from_jsonb_with_draft_wrapper';
+
+-- Registry, formats and keywords
+
+CREATE FUNCTION jsonschema_registry_invalidate()
+RETURNS trigger
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'jsonschema_registry_invalidate_wrapper';
+
+CREATE SCHEMA jsonschema;
+
+CREATE TABLE jsonschema.registry (
+    uri text PRIMARY KEY,
+    schema jsonb NOT NULL
+);
+
+GRANT USAGE ON SCHEMA jsonschema TO PUBLIC;
+GRANT SELECT ON jsonschema.registry TO PUBLIC;
+
+SELECT pg_catalog.pg_extension_config_dump('jsonschema.registry', '');
+
+CREATE TRIGGER registry_invalidate
+    AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON jsonschema.registry
+    FOR EACH STATEMENT EXECUTE FUNCTION jsonschema_registry_invalidate();
+
+CREATE TABLE jsonschema.formats (
+    name text PRIMARY KEY,
+    checker regprocedure NOT NULL
+);
+
+GRANT SELECT ON jsonschema.formats TO PUBLIC;
+
+SELECT pg_catalog.pg_extension_config_dump('jsonschema.formats', '');
+
+CREATE TRIGGER formats_invalidate
+    AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON jsonschema.formats
+    FOR EACH STATEMENT EXECUTE FUNCTION jsonschema_registry_invalidate();
+
+CREATE FUNCTION jsonschema_register_format(name text, checker regprocedure)
+RETURNS void
+LANGUAGE plpgsql
+AS $$
+BEGIN
+    IF NOT EXISTS (
+        SELECT FROM pg_catalog.pg_proc p
+        WHERE p.oid = $2
+          AND p.pronargs = 1
+          AND p.proargtypes[0] = 'text'::regtype
+          AND p.prorettype = 'boolean'::regtype
+          AND NOT p.proretset
+    ) THEN
+        RAISE EXCEPTION 'format checker % must take text and return boolean', $2;
+    END IF;
+
+    INSERT INTO jsonschema.formats (name, checker)
+    VALUES ($1, $2)
+    ON CONFLICT ON CONSTRAINT formats_pkey DO UPDATE SET checker = EXCLUDED.checker;
+END;
+$$;
+
+CREATE TABLE jsonschema.keywords (
+    name text PRIMARY KEY,
+    validator regprocedure NOT NULL
+);
+
+GRANT SELECT ON jsonschema.keywords TO PUBLIC;
+
+SELECT pg_catalog.pg_extension_config_dump('jsonschema.keywords', '');
+
+CREATE TRIGGER keywords_invalidate
+    AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON jsonschema.keywords
+    FOR EACH STATEMENT EXECUTE FUNCTION jsonschema_registry_invalidate();
+
+CREATE FUNCTION jsonschema_register_keyword(name text, validator regprocedure)
+RETURNS void
+LANGUAGE plpgsql
+AS $$
+BEGIN
+    IF NOT EXISTS (
+        SELECT FROM pg_catalog.pg_proc p
+        WHERE p.oid = $2
+          AND p.pronargs = 2
+          AND p.proargtypes[0] = 'jsonb'::regtype
+          AND p.proargtypes[1] = 'jsonb'::regtype
+          AND p.prorettype = 'text'::regtype
+          AND NOT p.proretset
+    ) THEN
+        RAISE EXCEPTION 'keyword validator % must take (jsonb, jsonb) and return text', $2;
+    END IF;
+
+    -- Keywords of any supported draft, and this extension's own, keep
+    -- their meaning.
+    IF $1 LIKE 'x-pg-%' OR $1 = ANY (ARRAY[
+        '$schema', '$id', 'id', '$ref', '$anchor', '$dynamicRef', '$dynamicAnchor',
+        '$recursiveRef', '$recursiveAnchor', '$vocabulary', '$comment', '$defs',
+        'definitions', 'allOf', 'anyOf', 'oneOf', 'not', 'if', 'then', 'else',
+        'dependentSchemas', 'prefixItems', 'items', 'additionalItems', 'contains',
+        'properties', 'patternProperties', 'additionalProperties', 'propertyNames',
+        'unevaluatedItems', 'unevaluatedProperties', 'type', 'enum', 'const',
+        'multipleOf', 'maximum', 'exclusiveMaximum', 'minimum', 'exclusiveMinimum',
+        'maxLength', 'minLength', 'pattern', 'maxItems', 'minItems', 'uniqueItems',
+        'maxContains', 'minContains', 'maxProperties', 'minProperties', 'required',
+        'dependentRequired', 'dependencies', 'format', 'contentEncoding',
+        'contentMediaType', 'contentSchema', 'title', 'description', 'default',
+        'deprecated', 'readOnly', 'writeOnly', 'examples'
+    ]) THEN
+        RAISE EXCEPTION 'keyword name "%" is reserved', $1;
+    END IF;
+
+    INSERT INTO jsonschema.keywords (name, validator)
+    VALUES ($1, $2)
+    ON CONFLICT ON CONSTRAINT keywords_pkey DO UPDATE SET validator = EXCLUDED.validator;
+END;
+$$;
+
+CREATE FUNCTION jsonschema_by_name(name text)
+RETURNS jsonschema
+STABLE STRICT PARALLEL SAFE
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'jsonschema_by_name_wrapper';
+
+CREATE FUNCTION jsonb_matches_named_schema(name text, instance jsonb)
+RETURNS boolean
+STABLE STRICT PARALLEL SAFE
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'jsonb_matches_named_schema_wrapper';
+
+-- Cache introspection
+
+CREATE FUNCTION jsonschema_cache_stats()
+RETURNS TABLE (
+    entries integer,
+    capacity integer,
+    hits bigint,
+    misses bigint,
+    evictions bigint,
+    compile_time_ms double precision
+)
+PARALLEL RESTRICTED
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'jsonschema_cache_stats_wrapper';
+
+CREATE FUNCTION jsonschema_cache_entries()
+RETURNS TABLE (fingerprint bytea, hits bigint, bytes bigint)
+PARALLEL RESTRICTED
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'jsonschema_cache_entries_wrapper';
+
+CREATE FUNCTION jsonschema_cache_reset()
+RETURNS void
+PARALLEL RESTRICTED
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'jsonschema_cache_reset_wrapper';
+
+-- Fingerprints and binary I/O
+
+CREATE FUNCTION jsonschema_fingerprint(schema jsonschema)
+RETURNS bytea
+IMMUTABLE STRICT PARALLEL SAFE
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'jsonschema_fingerprint_wrapper';
+
+CREATE FUNCTION jsonschema_send(schema jsonschema)
+RETURNS bytea
+IMMUTABLE STRICT PARALLEL SAFE
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'jsonschema_send_wrapper';
+
+CREATE FUNCTION jsonschema_recv(internal internal)
+RETURNS jsonschema
+IMMUTABLE STRICT PARALLEL SAFE
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'jsonschema_recv_wrapper';
+
+ALTER TYPE jsonschema SET (SEND = jsonschema_send, RECEIVE = jsonschema_recv);
+
+-- Match operators
+
+CREATE FUNCTION json_not_matches_compiled_schema(schema jsonschema, instance json)
+RETURNS boolean
+IMMUTABLE STRICT PARALLEL SAFE
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'json_not_matches_compiled_schema_wrapper';
+
+CREATE FUNCTION jsonb_not_matches_compiled_schema(schema jsonschema, instance jsonb)
+RETURNS boolean
+IMMUTABLE STRICT PARALLEL SAFE
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'jsonb_not_matches_compiled_schema_wrapper';
+
+CREATE FUNCTION json_matches_jsonschema(instance json, schema jsonschema)
+RETURNS boolean
+IMMUTABLE STRICT PARALLEL SAFE
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'json_matches_jsonschema_wrapper';
+
+CREATE FUNCTION jsonb_matches_jsonschema(instance jsonb, schema jsonschema)
+RETURNS boolean
+IMMUTABLE STRICT PARALLEL SAFE
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'jsonb_matches_jsonschema_wrapper';
+
+CREATE FUNCTION json_not_matches_jsonschema(instance json, schema jsonschema)
+RETURNS boolean
+IMMUTABLE STRICT PARALLEL SAFE
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'json_not_matches_jsonschema_wrapper';
+
+CREATE FUNCTION jsonb_not_matches_jsonschema(instance jsonb, schema jsonschema)
+RETURNS boolean
+IMMUTABLE STRICT PARALLEL SAFE
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'jsonb_not_matches_jsonschema_wrapper';
+
+CREATE OPERATOR @~ (
+    LEFTARG = json, RIGHTARG = jsonschema, FUNCTION = json_matches_jsonschema,
+    COMMUTATOR = ~@, NEGATOR = !@~,
+    RESTRICT = matchingsel, JOIN = matchingjoinsel
+);
+CREATE OPERATOR ~@ (
+    LEFTARG = jsonschema, RIGHTARG = json, FUNCTION = json_matches_compiled_schema,
+    COMMUTATOR = @~, NEGATOR = !~@,
+    RESTRICT = matchingsel, JOIN = matchingjoinsel
+);
+CREATE OPERATOR !@~ (
+    LEFTARG = json, RIGHTARG = jsonschema, FUNCTION = json_not_matches_jsonschema,
+    COMMUTATOR = !~@, NEGATOR = @~,
+    RESTRICT = matchingsel, JOIN = matchingjoinsel
+);
+CREATE OPERATOR !~@ (
+    LEFTARG = jsonschema, RIGHTARG = json, FUNCTION = json_not_matches_compiled_schema,
+    COMMUTATOR = !@~, NEGATOR = ~@,
+    RESTRICT = matchingsel, JOIN = matchingjoinsel
+);
+
+CREATE OPERATOR @~ (
+    LEFTARG = jsonb, RIGHTARG = jsonschema, FUNCTION = jsonb_matches_jsonschema,
+    COMMUTATOR = ~@, NEGATOR = !@~,
+    RESTRICT = matchingsel, JOIN = matchingjoinsel
+);
+CREATE OPERATOR ~@ (
+    LEFTARG = jsonschema, RIGHTARG = jsonb, FUNCTION = jsonb_matches_compiled_schema,
+    COMMUTATOR = @~, NEGATOR = !~@,
+    RESTRICT = matchingsel, JOIN = matchingjoinsel
+);
+CREATE OPERATOR !@~ (
+    LEFTARG = jsonb, RIGHTARG = jsonschema, FUNCTION = jsonb_not_matches_jsonschema,
+    COMMUTATOR = !~@, NEGATOR = @~,
+    RESTRICT = matchingsel, JOIN = matchingjoinsel
+);
+CREATE OPERATOR !~@ (
+    LEFTARG = jsonschema, RIGHTARG = jsonb, FUNCTION = jsonb_not_matches_compiled_schema,
+    COMMUTATOR = !@~, NEGATOR = ~@,
+    RESTRICT = matchingsel, JOIN = matchingjoinsel
+);
+
+-- Selectivity of the match functions
+
+CREATE FUNCTION jsonschema_match_support(request internal)
+RETURNS internal
+IMMUTABLE STRICT PARALLEL SAFE
+LANGUAGE c
+AS 'MODULE_PATHNAME', 'jsonschema_match_support_wrapper';
+
+ALTER FUNCTION json_matches_compiled_schema(jsonschema, json) SUPPORT jsonschema_match_support;
+ALTER FUNCTION jsonb_matches_compiled_schema(jsonschema, jsonb) SUPPORT jsonschema_match_support;
+ALTER FUNCTION json_not_matches_compiled_schema(jsonschema, json) SUPPORT jsonschema_match_support;
+ALTER FUNCTION jsonb_not_matches_compiled_schema(jsonschema, jsonb) SUPPORT jsonschema_match_support;
+ALTER FUNCTION json_matches_jsonschema(json, jsonschema) SUPPORT jsonschema_match_support;
+ALTER FUNCTION jsonb_matches_jsonschema(jsonb, jsonschema) SUPPORT jsonschema_match_support;
+ALTER FUNCTION json_not_matches_jsonschema(json, jsonschema) SUPPORT jsonschema_match_support;
+ALTER FUNCTION jsonb_not_matches_jsonschema(jsonb, jsonschema) SUPPORT jsonschema_match_support;
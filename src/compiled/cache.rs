@@ -1,30 +1,236 @@
-/// Backend-local LRU cache mapping canonical schema strings to compiled validators.
+/// Backend-local LRU cache mapping schema fingerprints to compiled validators.
 ///
 /// PostgreSQL backends are single-threaded OS processes, so a `thread_local`
 /// `RefCell` is sufficient — no mutex needed.
-use std::cell::RefCell;
+///
+/// The cache is bounded by `pg_jsonschema.cache_size` entries and, optionally,
+/// by `pg_jsonschema.cache_max_bytes` of canonical schema text. Both settings
+/// are re-read on every access, so changing them takes effect immediately.
+///
+/// Keying by [`Fingerprint`] makes a lookup cost the same for any schema size.
+///
+/// Pattern settings are not part of the key; the cache is emptied when they
+/// change (see [`super::patterns`]).
+use std::cell::{Cell, RefCell};
 use std::num::NonZeroUsize;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-type Cache = lru::LruCache<String, Arc<jsonschema::Validator>>;
+use super::{Fingerprint, JsonSchema, patterns};
+use crate::guc;
 
-const CAPACITY: NonZeroUsize = NonZeroUsize::new(128).expect("128 is non zero");
+struct Entry {
+    validator: Arc<jsonschema::Validator>,
+    hits: u64,
+    /// Length of the canonical schema string.
+    bytes: usize,
+}
+
+/// Counters reported by `jsonschema_cache_stats()`, cleared by [`reset`].
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Stats {
+    pub(crate) entries: usize,
+    pub(crate) capacity: usize,
+    /// Lookups answered by this cache. Calls served by a callsite's own
+    /// validator (see [`super::callsite`]) never get here and aren't counted.
+    pub(crate) hits: u64,
+    pub(crate) misses: u64,
+    pub(crate) evictions: u64,
+    pub(crate) compile_time: Duration,
+}
+
+/// A cached validator as reported by `jsonschema_cache_entries()`.
+pub(crate) struct EntryInfo {
+    pub(crate) fingerprint: Fingerprint,
+    pub(crate) hits: u64,
+    pub(crate) bytes: usize,
+}
+
+struct Cache {
+    entries: lru::LruCache<Fingerprint, Entry>,
+    /// Total length of the cached canonical schema strings.
+    bytes: usize,
+    stats: Stats,
+}
+
+impl Cache {
+    /// Applies the current GUC limits, evicting least recently used entries.
+    fn configure(&mut self) {
+        let capacity = NonZeroUsize::new(guc::CACHE_SIZE.get().max(1) as usize)
+            .expect("capacity is at least one");
+        if self.entries.cap() != capacity {
+            while self.entries.len() > capacity.get() {
+                self.pop_lru();
+            }
+            self.entries.resize(capacity);
+        }
+        self.trim_to_max_bytes();
+    }
+
+    fn trim_to_max_bytes(&mut self) {
+        let max_bytes = guc::CACHE_MAX_BYTES.get() as usize;
+        while max_bytes > 0 && self.bytes > max_bytes && self.pop_lru() {}
+    }
+
+    fn pop_lru(&mut self) -> bool {
+        match self.entries.pop_lru() {
+            Some((_, entry)) => {
+                self.bytes -= entry.bytes;
+                self.stats.evictions += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn insert(&mut self, schema: &JsonSchema, validator: Arc<jsonschema::Validator>) {
+        let bytes = schema.value.len();
+        self.bytes += bytes;
+        let entry = Entry {
+            validator,
+            hits: 0,
+            bytes,
+        };
+        if let Some((fingerprint, evicted)) = self.entries.push(*schema.fingerprint(), entry) {
+            self.bytes -= evicted.bytes;
+            // Replacing the schema's own entry is not an eviction.
+            if fingerprint != *schema.fingerprint() {
+                self.stats.evictions += 1;
+            }
+        }
+        self.trim_to_max_bytes();
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.bytes = 0;
+    }
+}
 
 thread_local! {
-    static CACHE: RefCell<Cache> = RefCell::new(lru::LruCache::new(CAPACITY));
+    static CACHE: RefCell<Cache> = RefCell::new(Cache {
+        entries: lru::LruCache::new(NonZeroUsize::MIN),
+        bytes: 0,
+        stats: Stats::default(),
+    });
+    static GENERATION: Cell<u64> = const { Cell::new(0) };
+    /// Pattern settings the cached validators were compiled under.
+    static PATTERNS: Cell<Option<patterns::Settings>> = const { Cell::new(None) };
+}
+
+/// Drops every validator if the pattern settings changed since the last check.
+fn check_pattern_settings() {
+    let current = patterns::Settings::current();
+    if PATTERNS
+        .replace(Some(current))
+        .is_some_and(|previous| previous != current)
+    {
+        invalidate();
+    }
 }
 
 /// Returns the cached validator for `schema`, inserting one produced by `f` on a miss.
+///
+/// The cache is not borrowed while `f` runs: compiling may read the registry
+/// through SPI, which can process invalidation messages that call [`invalidate`].
 pub(super) fn get_or_insert(
-    schema: &str,
+    schema: &JsonSchema,
     f: impl FnOnce() -> Arc<jsonschema::Validator>,
 ) -> Arc<jsonschema::Validator> {
+    let Ok(validator) = try_get_or_insert(schema, || Ok::<_, std::convert::Infallible>(f()));
+    validator
+}
+
+/// Like [`get_or_insert`] for a fallible `f`; nothing is cached when it fails.
+pub(super) fn try_get_or_insert<E>(
+    schema: &JsonSchema,
+    f: impl FnOnce() -> Result<Arc<jsonschema::Validator>, E>,
+) -> Result<Arc<jsonschema::Validator>, E> {
+    check_pattern_settings();
+    let cached = CACHE.with_borrow_mut(|c| {
+        c.configure();
+        let entry = c.entries.get_mut(schema.fingerprint())?;
+        entry.hits += 1;
+        let validator = Arc::clone(&entry.validator);
+        c.stats.hits += 1;
+        Some(validator)
+    });
+    if let Some(v) = cached {
+        return Ok(v);
+    }
+    compile_and_insert(schema, f)
+}
+
+/// Caches the validator produced by `f` for `schema`, replacing the cached one
+/// if any; nothing is cached when `f` fails.
+pub(super) fn try_insert<E>(
+    schema: &JsonSchema,
+    f: impl FnOnce() -> Result<Arc<jsonschema::Validator>, E>,
+) -> Result<Arc<jsonschema::Validator>, E> {
+    check_pattern_settings();
+    CACHE.with_borrow_mut(|c| c.configure());
+    compile_and_insert(schema, f)
+}
+
+fn compile_and_insert<E>(
+    schema: &JsonSchema,
+    f: impl FnOnce() -> Result<Arc<jsonschema::Validator>, E>,
+) -> Result<Arc<jsonschema::Validator>, E> {
+    let started = Instant::now();
+    let validator = f()?;
+    let elapsed = started.elapsed();
+    CACHE.with_borrow_mut(|c| {
+        c.stats.misses += 1;
+        c.stats.compile_time += elapsed;
+        c.insert(schema, Arc::clone(&validator));
+    });
+    Ok(validator)
+}
+
+/// Returns the current size, capacity and counters of the cache.
+pub(crate) fn stats() -> Stats {
     CACHE.with_borrow_mut(|c| {
-        if let Some(v) = c.get(schema) {
-            return Arc::clone(v);
+        c.configure();
+        Stats {
+            entries: c.entries.len(),
+            capacity: c.entries.cap().get(),
+            ..c.stats
         }
-        let validator = f();
-        c.put(schema.to_owned(), Arc::clone(&validator));
-        validator
     })
 }
+
+/// Lists cached validators from most to least recently used.
+pub(crate) fn entries() -> Vec<EntryInfo> {
+    CACHE.with_borrow(|c| {
+        c.entries
+            .iter()
+            .map(|(fingerprint, entry)| EntryInfo {
+                fingerprint: *fingerprint,
+                hits: entry.hits,
+                bytes: entry.bytes,
+            })
+            .collect()
+    })
+}
+
+/// Empties the cache, zeroes its counters and marks all callsite caches stale.
+pub(crate) fn reset() {
+    CACHE.with_borrow_mut(|c| {
+        c.clear();
+        c.stats = Stats::default();
+    });
+    GENERATION.set(GENERATION.get() + 1);
+}
+
+/// Bumped by [`invalidate`], including when the pattern settings change;
+/// callsite caches compiled under an older generation are stale.
+pub(super) fn generation() -> u64 {
+    check_pattern_settings();
+    GENERATION.get()
+}
+
+/// Drops every cached validator and marks all callsite caches stale.
+pub(super) fn invalidate() {
+    CACHE.with_borrow_mut(|c| c.clear());
+    GENERATION.set(GENERATION.get() + 1);
+}
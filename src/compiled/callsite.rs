@@ -1,19 +1,26 @@
 use std::sync::Arc;
 
 use pgrx::*;
+use serde_json::Value;
 
-use super::{JsonSchema, cache, compile_from_str};
+use super::{Fingerprint, JsonSchema, cache, compile_from_str};
 
-fn get_or_compile(schema: &JsonSchema) -> Arc<jsonschema::Validator> {
-    cache::get_or_insert(&schema.value, || compile_from_str(&schema.value))
+/// Returns a compiled validator for `schema` from the backend-local LRU alone.
+///
+/// For callers that cannot use `fn_extra`, such as set-returning functions
+/// where it already holds the `FuncCallContext`.
+pub(crate) fn get_or_compile(schema: &JsonSchema) -> Arc<jsonschema::Validator> {
+    cache::get_or_insert(schema, || compile_from_str(&schema.value))
 }
 
 /// Per-callsite validator cache in `fcinfo->flinfo->fn_extra`.
 struct FnExtraCache {
-    schema: String,
+    fingerprint: Fingerprint,
     validator: Arc<jsonschema::Validator>,
     info: *mut pg_sys::FmgrInfo,
     stable_schema_arg: bool,
+    /// [`cache::generation`] at compile time; a mismatch forces a refresh.
+    generation: u64,
     /// MemoryContextCallback; fires `drop_fn_extra_cache` when fn_mcxt is reset.
     callback: pg_sys::MemoryContextCallback,
 }
@@ -32,23 +39,37 @@ unsafe extern "C-unwind" fn drop_fn_extra_cache(arg: *mut std::ffi::c_void) {
 ///
 /// **L1** — per-callsite slot in `fcinfo->flinfo->fn_extra` (lifetime: `fn_mcxt`).
 /// When the schema argument is stable (immutable expression), the slot is reused
-/// unconditionally; otherwise it is reused on a string match.
+/// unconditionally; otherwise it is reused when the fingerprints match. Either way the
+/// slot is dropped once the registry is invalidated (see [`cache::invalidate`]).
 ///
 /// **L2** — backend-local LRU (see [`super::cache`]).  Hit on L1 miss.
 ///
 /// # Why two levels?
 ///
-/// The L2 LRU lookup hashes the full canonical schema string on every call.
-/// For small schemas this is negligible, but large schemas make it expensive:
-/// a 3.3 MB FHIR schema hashed across 20k rows takes ~13 s via LRU alone vs
-/// ~1.8 s with the L1 callsite cache, which reduces each hot-path lookup to a
-/// pointer dereference.
+/// The L2 LRU lookup is keyed by the schema's fingerprint, so it no longer
+/// hashes the full canonical string (a 3.3 MB FHIR schema across 20k rows used
+/// to take ~13 s via LRU alone vs ~1.8 s with the L1 cache). The L1 callsite
+/// cache still saves detoasting and decoding the schema argument when it is
+/// stable, reducing each hot-path lookup to a pointer dereference.
 ///
 /// # Safety
 /// `fcinfo` must be a valid, non-null `FunctionCallInfo` for the current call.
 pub(crate) unsafe fn fn_extra_get_or_compile(
     schema: &JsonSchema,
     fcinfo: pg_sys::FunctionCallInfo,
+) -> Arc<jsonschema::Validator> {
+    unsafe { fn_extra_get_or_compile_arg(schema, 0, fcinfo) }
+}
+
+/// [`fn_extra_get_or_compile`] for a schema passed as argument `argnum`
+/// (zero-based) rather than first.
+///
+/// # Safety
+/// `fcinfo` must be a valid, non-null `FunctionCallInfo` for the current call.
+pub(crate) unsafe fn fn_extra_get_or_compile_arg(
+    schema: &JsonSchema,
+    argnum: i32,
+    fcinfo: pg_sys::FunctionCallInfo,
 ) -> Arc<jsonschema::Validator> {
     unsafe {
         let flinfo = (*fcinfo).flinfo;
@@ -59,14 +80,16 @@ pub(crate) unsafe fn fn_extra_get_or_compile(
         };
 
         // L1 hit: schema matches (or arg is stable, so it can't change).
+        let generation = cache::generation();
         if let Some(cached) = cached_ptr.map(|ptr| &*ptr)
-            && (cached.stable_schema_arg || cached.schema.as_str() == schema.value.as_str())
+            && cached.generation == generation
+            && (cached.stable_schema_arg || cached.fingerprint == *schema.fingerprint())
         {
             return Arc::clone(&cached.validator);
         }
 
         // L1 miss: pay the stability check once, then refresh or allocate.
-        let stable_schema_arg = pg_sys::get_fn_expr_arg_stable(flinfo, 0);
+        let stable_schema_arg = pg_sys::get_fn_expr_arg_stable(flinfo, argnum);
 
         // Cache miss: refresh the callsite entry.
         if let Some(cached_ptr) = cached_ptr {
@@ -76,10 +99,11 @@ pub(crate) unsafe fn fn_extra_get_or_compile(
             let old_entry = std::mem::replace(
                 cached,
                 FnExtraCache {
-                    schema: schema.value.clone(),
+                    fingerprint: *schema.fingerprint(),
                     validator: Arc::clone(&validator),
                     info: flinfo,
                     stable_schema_arg,
+                    generation,
                     callback: pg_sys::MemoryContextCallback {
                         func: Some(drop_fn_extra_cache),
                         arg: cached_ptr as *mut std::ffi::c_void,
@@ -100,10 +124,11 @@ pub(crate) unsafe fn fn_extra_get_or_compile(
         std::ptr::write(
             cache_ptr,
             FnExtraCache {
-                schema: schema.value.clone(),
+                fingerprint: *schema.fingerprint(),
                 validator: Arc::clone(&validator),
                 info: flinfo,
                 stable_schema_arg,
+                generation,
                 callback: pg_sys::MemoryContextCallback {
                     func: Some(drop_fn_extra_cache),
                     arg: cache_ptr as *mut std::ffi::c_void,
@@ -121,3 +146,28 @@ pub(crate) unsafe fn fn_extra_get_or_compile(
         validator
     }
 }
+
+/// [`fn_extra_get_or_compile`] for a schema passed as plain `json`.
+///
+/// A stable schema argument is only canonicalized on an L1 miss; otherwise it is
+/// canonicalized on every call to compare against the slot. Invalid schemas are
+/// returned to the caller and never cached.
+///
+/// # Safety
+/// `fcinfo` must be a valid, non-null `FunctionCallInfo` for the current call.
+pub(crate) unsafe fn fn_extra_get_or_compile_value(
+    schema: &Value,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Result<Arc<jsonschema::Validator>, jsonschema::ValidationError<'static>> {
+    unsafe {
+        let cached = ((*(*fcinfo).flinfo).fn_extra as *const FnExtraCache).as_ref();
+        if let Some(cached) = cached
+            && cached.stable_schema_arg
+            && cached.generation == cache::generation()
+        {
+            return Ok(Arc::clone(&cached.validator));
+        }
+        let schema = JsonSchema::try_compile(schema)?;
+        Ok(fn_extra_get_or_compile(&schema, fcinfo))
+    }
+}
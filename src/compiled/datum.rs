@@ -0,0 +1,214 @@
+//! On-disk representation of `jsonschema` values.
+//!
+//! A value is a varlena holding a fixed-size header, then the canonical schema
+//! text:
+//!
+//! ```text
+//! | version (1 byte) | fingerprint (16 bytes) | canonical JSON ... |
+//! ```
+//!
+//! TOAST compresses and moves large values out of line as usual. As the header
+//! comes first, equality and hashing fetch (and decompress) only its bytes with
+//! a slice detoast, never the schema itself. Values written by earlier
+//! versions, which pgrx stored as CBOR, are still read, with the compile
+//! options they were compiled with recorded as they would be today.
+use pgrx::{
+    callconv::{Arg, ArgAbi, BoxRet, FcInfo},
+    datum::UnboxDatum,
+    pgrx_sql_entity_graph::metadata::{
+        ArgumentError, Returns, ReturnsError, SqlMapping, SqlTranslatable,
+    },
+    *,
+};
+use sha2::{Digest, Sha256};
+
+use super::JsonSchema;
+
+/// Format version leading every value written by this version.
+const FORMAT_VERSION: u8 = 1;
+
+/// First byte of the CBOR map that earlier versions stored.
+const LEGACY_CBOR_MAP: u8 = 0xa1;
+
+pub(crate) const FINGERPRINT_LEN: usize = 16;
+
+const HEADER_LEN: usize = 1 + FINGERPRINT_LEN;
+
+/// Identifies a canonical schema: the first 128 bits of its SHA-256 digest.
+pub(crate) type Fingerprint = [u8; FINGERPRINT_LEN];
+
+pub(crate) fn fingerprint(canonical: &str) -> Fingerprint {
+    let digest = Sha256::digest(canonical.as_bytes());
+    let mut fingerprint = Fingerprint::default();
+    fingerprint.copy_from_slice(&digest[..FINGERPRINT_LEN]);
+    fingerprint
+}
+
+#[derive(serde::Deserialize)]
+struct Legacy {
+    value: String,
+}
+
+/// Decodes a detoasted value.
+///
+/// # Safety
+/// `varlena` must point to a detoasted `jsonschema` value.
+unsafe fn decode(varlena: *mut pg_sys::varlena) -> JsonSchema {
+    let bytes = unsafe {
+        std::slice::from_raw_parts(
+            vardata_any(varlena) as *const u8,
+            varsize_any_exhdr(varlena),
+        )
+    };
+    match bytes.first() {
+        Some(&FORMAT_VERSION) if bytes.len() >= HEADER_LEN => {
+            let (header, text) = bytes.split_at(HEADER_LEN);
+            let value = std::str::from_utf8(text)
+                .unwrap_or_else(|err| pgrx::error!("corrupt jsonschema value: {err}"));
+            JsonSchema {
+                value: value.to_owned(),
+                fingerprint: header[1..].try_into().unwrap(),
+            }
+        }
+        Some(&LEGACY_CBOR_MAP) => {
+            let Legacy { value } = unsafe { pgrx::datum::cbor_decode(varlena) };
+            JsonSchema::from_legacy(&value)
+        }
+        Some(&version) => pgrx::error!("unsupported jsonschema format version {version}"),
+        None => pgrx::error!("corrupt jsonschema value: no data"),
+    }
+}
+
+impl IntoDatum for JsonSchema {
+    fn into_datum(self) -> Option<pg_sys::Datum> {
+        let len = pg_sys::VARHDRSZ + HEADER_LEN + self.value.len();
+        let varsize =
+            i32::try_from(len).unwrap_or_else(|_| pgrx::error!("jsonschema value is too large"));
+        unsafe {
+            let varlena = pg_sys::palloc(len) as *mut pg_sys::varlena;
+            set_varsize_4b(varlena, varsize);
+            let data = (varlena as *mut u8).add(pg_sys::VARHDRSZ);
+            data.write(FORMAT_VERSION);
+            std::ptr::copy_nonoverlapping(self.fingerprint.as_ptr(), data.add(1), FINGERPRINT_LEN);
+            std::ptr::copy_nonoverlapping(
+                self.value.as_ptr(),
+                data.add(HEADER_LEN),
+                self.value.len(),
+            );
+            Some(pg_sys::Datum::from(varlena))
+        }
+    }
+
+    fn type_oid() -> pg_sys::Oid {
+        pgrx::wrappers::rust_regtypein::<Self>()
+    }
+}
+
+unsafe impl BoxRet for JsonSchema {
+    unsafe fn box_into<'fcx>(self, fcinfo: &mut FcInfo<'fcx>) -> pgrx::datum::Datum<'fcx> {
+        match self.into_datum() {
+            None => fcinfo.return_null(),
+            Some(datum) => unsafe { fcinfo.return_raw_datum(datum) },
+        }
+    }
+}
+
+impl FromDatum for JsonSchema {
+    unsafe fn from_polymorphic_datum(
+        datum: pg_sys::Datum,
+        is_null: bool,
+        _typoid: pg_sys::Oid,
+    ) -> Option<Self> {
+        if is_null {
+            return None;
+        }
+        unsafe {
+            Some(decode(pg_sys::pg_detoast_datum_packed(
+                datum.cast_mut_ptr(),
+            )))
+        }
+    }
+
+    unsafe fn from_datum_in_memory_context(
+        mut memory_context: PgMemoryContexts,
+        datum: pg_sys::Datum,
+        is_null: bool,
+        _typoid: pg_sys::Oid,
+    ) -> Option<Self> {
+        if is_null {
+            return None;
+        }
+        unsafe {
+            memory_context.switch_to(|_| {
+                let varlena = pg_sys::pg_detoast_datum_copy(datum.cast_mut_ptr());
+                Some(decode(varlena))
+            })
+        }
+    }
+}
+
+unsafe impl UnboxDatum for JsonSchema {
+    type As<'dat> = Self;
+
+    unsafe fn unbox<'dat>(datum: pgrx::datum::Datum<'dat>) -> Self::As<'dat> {
+        unsafe { Self::from_datum(std::mem::transmute(datum), false).unwrap() }
+    }
+}
+
+unsafe impl<'fcx> ArgAbi<'fcx> for JsonSchema {
+    unsafe fn unbox_arg_unchecked(arg: Arg<'_, 'fcx>) -> Self {
+        let index = arg.index();
+        unsafe {
+            arg.unbox_arg_using_from_datum()
+                .unwrap_or_else(|| panic!("argument {index} must not be null"))
+        }
+    }
+}
+
+/// A `jsonschema` argument of which only the fingerprint is read.
+pub(crate) struct SchemaFingerprint(pub(crate) Fingerprint);
+
+impl FromDatum for SchemaFingerprint {
+    unsafe fn from_polymorphic_datum(
+        datum: pg_sys::Datum,
+        is_null: bool,
+        typoid: pg_sys::Oid,
+    ) -> Option<Self> {
+        if is_null {
+            return None;
+        }
+        unsafe {
+            let header = pg_sys::pg_detoast_datum_slice(datum.cast_mut_ptr(), 0, HEADER_LEN as i32);
+            let bytes = std::slice::from_raw_parts(
+                vardata_any(header) as *const u8,
+                varsize_any_exhdr(header),
+            );
+            if bytes.len() == HEADER_LEN && bytes[0] == FORMAT_VERSION {
+                return Some(Self(bytes[1..].try_into().unwrap()));
+            }
+            // Legacy values have no header; read them in full.
+            JsonSchema::from_polymorphic_datum(datum, false, typoid)
+                .map(|schema| Self(schema.fingerprint))
+        }
+    }
+}
+
+unsafe impl<'fcx> ArgAbi<'fcx> for SchemaFingerprint {
+    unsafe fn unbox_arg_unchecked(arg: Arg<'_, 'fcx>) -> Self {
+        let index = arg.index();
+        unsafe {
+            arg.unbox_arg_using_from_datum()
+                .unwrap_or_else(|| panic!("argument {index} must not be null"))
+        }
+    }
+}
+
+unsafe impl SqlTranslatable for SchemaFingerprint {
+    fn argument_sql() -> Result<SqlMapping, ArgumentError> {
+        Ok(SqlMapping::literal("jsonschema"))
+    }
+
+    fn return_sql() -> Result<Returns, ReturnsError> {
+        Ok(Returns::One(SqlMapping::literal("jsonschema")))
+    }
+}
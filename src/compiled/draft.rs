@@ -0,0 +1,56 @@
+//! Explicit JSON Schema draft selection.
+//!
+//! The chosen draft is recorded as the schema's `$schema` meta-schema URI, so
+//! it is part of the canonical form: it survives text round-trips, takes part
+//! in equality and keys the validator caches like any other schema content.
+use jsonschema::Draft;
+use serde_json::{Map, Value};
+
+use crate::guc::{self, DefaultDraft};
+
+/// Parses a draft name such as `4`, `draft7`, `2019-09` or `2020-12`.
+pub(crate) fn parse(name: &str) -> Draft {
+    match name
+        .trim_start_matches("draft")
+        .trim_start_matches(['-', ' '])
+    {
+        "4" | "04" => Draft::Draft4,
+        "6" | "06" => Draft::Draft6,
+        "7" | "07" => Draft::Draft7,
+        "2019-09" | "201909" => Draft::Draft201909,
+        "2020-12" | "202012" => Draft::Draft202012,
+        _ => pgrx::error!(
+            "unknown JSON Schema draft \"{name}\", expected one of: 4, 6, 7, 2019-09, 2020-12"
+        ),
+    }
+}
+
+pub(super) fn meta_schema_uri(draft: Draft) -> &'static str {
+    match draft {
+        Draft::Draft4 => "http://json-schema.org/draft-04/schema#",
+        Draft::Draft6 => "http://json-schema.org/draft-06/schema#",
+        Draft::Draft7 => "http://json-schema.org/draft-07/schema#",
+        Draft::Draft201909 => "https://json-schema.org/draft/2019-09/schema",
+        _ => "https://json-schema.org/draft/2020-12/schema",
+    }
+}
+
+/// The draft to record as the `$schema` of a schema object, if any.
+///
+/// An explicit `draft` always wins. Otherwise `pg_jsonschema.default_draft` is
+/// recorded for schemas that don't declare `$schema` themselves, so every
+/// stored schema object names its draft and reading it back in is a no-op.
+pub(super) fn resolve(object: &Map<String, Value>, draft: Option<Draft>) -> Option<Draft> {
+    match draft {
+        Some(draft) => Some(draft),
+        None if object.contains_key("$schema") => None,
+        None => match guc::DEFAULT_DRAFT.get() {
+            DefaultDraft::Auto => Some(Draft::default()),
+            DefaultDraft::Draft4 => Some(Draft::Draft4),
+            DefaultDraft::Draft6 => Some(Draft::Draft6),
+            DefaultDraft::Draft7 => Some(Draft::Draft7),
+            DefaultDraft::Draft201909 => Some(Draft::Draft201909),
+            DefaultDraft::Draft202012 => Some(Draft::Draft202012),
+        },
+    }
+}
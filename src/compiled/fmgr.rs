@@ -0,0 +1,113 @@
+//! SQL functions called by compiled validators.
+//!
+//! A [`Function`] is looked up once, when the validator is compiled. Validators
+//! outlive the statement compiling them, so its `FmgrInfo`, and whatever the
+//! function caches in `fn_extra`, live in a memory context of their own that
+//! is deleted along with the validator.
+//!
+//! Like any function call, calling it requires `EXECUTE` on the function. That
+//! is checked when it is looked up and again whenever the current user changed
+//! since, as a cached validator may be used by several roles.
+use std::cell::Cell;
+
+use pgrx::*;
+
+pub(super) struct Function {
+    oid: pg_sys::Oid,
+    flinfo: *mut pg_sys::FmgrInfo,
+    context: pg_sys::MemoryContext,
+    /// The user `EXECUTE` was last checked for.
+    checked_for: Cell<pg_sys::Oid>,
+}
+
+// Validators must be `Send + Sync`, but they are only ever used by the backend
+// that compiled them.
+unsafe impl Send for Function {}
+unsafe impl Sync for Function {}
+
+impl Function {
+    /// Looks up the function `oid`.
+    pub(super) fn new(oid: pg_sys::Oid) -> Self {
+        let checked_for = check_execute(oid);
+        unsafe {
+            // Created under the current context, so that it goes away with it
+            // if the lookup fails, and only then moved under the top context.
+            let context = pg_sys::AllocSetContextCreateExtended(
+                pg_sys::CurrentMemoryContext,
+                c"pg_jsonschema function".as_ptr(),
+                pg_sys::ALLOCSET_SMALL_MINSIZE as usize,
+                pg_sys::ALLOCSET_SMALL_INITSIZE as usize,
+                pg_sys::ALLOCSET_SMALL_MAXSIZE as usize,
+            );
+            let flinfo =
+                pg_sys::MemoryContextAllocZero(context, std::mem::size_of::<pg_sys::FmgrInfo>())
+                    as *mut pg_sys::FmgrInfo;
+            pg_sys::fmgr_info_cxt(oid, flinfo, context);
+            pg_sys::MemoryContextSetParent(context, pg_sys::TopMemoryContext);
+            Self {
+                oid,
+                flinfo,
+                context,
+                checked_for: Cell::new(checked_for),
+            }
+        }
+    }
+
+    /// Calls the function on non-NULL `args`, returning `None` for a NULL
+    /// result. Unlike `OidFunctionCall*Coll`, a NULL result is not an error.
+    pub(super) fn call(&self, args: &[pg_sys::Datum]) -> Option<pg_sys::Datum> {
+        if self.checked_for.get() != unsafe { pg_sys::GetUserId() } {
+            self.checked_for.set(check_execute(self.oid));
+        }
+        unsafe {
+            let fcinfo = pg_sys::palloc0(
+                std::mem::size_of::<pg_sys::FunctionCallInfoBaseData>()
+                    + std::mem::size_of::<pg_sys::NullableDatum>() * args.len(),
+            ) as *mut pg_sys::FunctionCallInfoBaseData;
+            (*fcinfo).flinfo = self.flinfo;
+            (*fcinfo).fncollation = pg_sys::DEFAULT_COLLATION_OID;
+            (*fcinfo).nargs = args.len() as _;
+            for (arg, &value) in (*fcinfo).args.as_mut_slice(args.len()).iter_mut().zip(args) {
+                arg.value = value;
+                arg.isnull = false;
+            }
+
+            let func = (*self.flinfo).fn_addr.expect("function has no address");
+            let result = pg_sys::ffi::pg_guard_ffi_boundary(|| func(fcinfo));
+            let isnull = (*fcinfo).isnull;
+            pg_sys::pfree(fcinfo.cast());
+            (!isnull).then_some(result)
+        }
+    }
+}
+
+/// Raises `permission denied for function` unless the current user may execute
+/// the function `oid`, returning the user.
+fn check_execute(oid: pg_sys::Oid) -> pg_sys::Oid {
+    unsafe {
+        let user = pg_sys::GetUserId();
+        #[cfg(any(feature = "pg13", feature = "pg14", feature = "pg15"))]
+        let result = pg_sys::pg_proc_aclcheck(oid, user, pg_sys::ACL_EXECUTE as _);
+        #[cfg(not(any(feature = "pg13", feature = "pg14", feature = "pg15")))]
+        let result = pg_sys::object_aclcheck(
+            pg_sys::ProcedureRelationId,
+            oid,
+            user,
+            pg_sys::ACL_EXECUTE as _,
+        );
+        if result != pg_sys::AclResult::ACLCHECK_OK {
+            pg_sys::aclcheck_error(
+                result,
+                pg_sys::ObjectType::OBJECT_FUNCTION,
+                pg_sys::get_func_name(oid),
+            );
+        }
+        user
+    }
+}
+
+impl Drop for Function {
+    fn drop(&mut self) {
+        unsafe { pg_sys::MemoryContextDelete(self.context) }
+    }
+}
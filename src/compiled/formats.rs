@@ -0,0 +1,125 @@
+//! Assertion of the `format` keyword.
+//!
+//! From draft 2019-09 on, `format` is an annotation unless asked otherwise.
+//! Whether a schema asserts formats is recorded in the schema itself as the
+//! [`KEYWORD`] annotation, so it is part of the canonical form and keys the
+//! validator caches like the rest of the schema.
+//!
+//! Formats registered in `jsonschema.formats` are checked by calling their
+//! SQL checker function. Changes to the table flush compiled validators the
+//! same way registry changes do (see [`super::registry`]).
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+};
+
+use pgrx::*;
+use serde_json::{Map, Value};
+
+use super::fmgr::Function;
+use crate::guc;
+
+thread_local! {
+    /// Contents of `jsonschema.formats`, loaded on first compile.
+    static REGISTERED: RefCell<Option<Rc<[(String, pg_sys::Oid)]>>> = const { RefCell::new(None) };
+    /// OID of `jsonschema.formats`, resolved along with its contents.
+    static FORMATS_OID: Cell<pg_sys::Oid> = const { Cell::new(pg_sys::InvalidOid) };
+}
+
+/// Schema keyword enabling (`true`) or disabling (`false`) format assertion.
+pub(crate) const KEYWORD: &str = "x-pg-validate-formats";
+
+/// The format assertion setting to record in a schema object, if any.
+///
+/// Schemas that don't set [`KEYWORD`] themselves record
+/// `pg_jsonschema.validate_formats`: `true` when it is on, and `null`, the
+/// draft's default, when it is off. Either way the stored schema carries the
+/// setting, so reading it back in under another setting doesn't change it.
+pub(super) fn resolve(object: &Map<String, Value>) -> Option<Value> {
+    (!object.contains_key(KEYWORD)).then(|| {
+        if guc::VALIDATE_FORMATS.get() {
+            Value::Bool(true)
+        } else {
+            Value::Null
+        }
+    })
+}
+
+/// Format assertion as recorded in `schema`, `None` for the draft's default.
+///
+/// Anything but a boolean, `null` included, leaves the draft's default.
+pub(super) fn recorded(schema: &Value) -> Option<bool> {
+    schema.get(KEYWORD).and_then(Value::as_bool)
+}
+
+/// Adds every format registered in `jsonschema.formats` to `options`.
+pub(super) fn with_registered(
+    mut options: jsonschema::ValidationOptions<'_>,
+) -> jsonschema::ValidationOptions<'_> {
+    for (name, checker) in registered().iter() {
+        let checker = Function::new(*checker);
+        options = options.with_format(name.as_str(), move |value: &str| check(&checker, value));
+    }
+    options
+}
+
+fn registered() -> Rc<[(String, pg_sys::Oid)]> {
+    if let Some(formats) = REGISTERED.with_borrow(Option::clone) {
+        return formats;
+    }
+    match load().unwrap_or_else(|err| pgrx::error!("failed to load registered formats: {err}")) {
+        Some(formats) => {
+            REGISTERED.set(Some(Rc::clone(&formats)));
+            formats
+        }
+        // Not remembered, so the table is read once an upgrade creates it.
+        None => Rc::from([]),
+    }
+}
+
+/// Reads `jsonschema.formats`, `None` if it doesn't exist, as in an
+/// installation not yet upgraded from 0.3.4.
+fn load() -> Result<Option<Rc<[(String, pg_sys::Oid)]>>, pgrx::spi::Error> {
+    let Some(oid) =
+        Spi::get_one::<pg_sys::Oid>("SELECT pg_catalog.to_regclass('jsonschema.formats')::oid")?
+    else {
+        return Ok(None);
+    };
+    FORMATS_OID.set(oid);
+    Spi::connect(|client| {
+        client
+            .select(
+                "SELECT name, checker::oid FROM jsonschema.formats",
+                None,
+                &[],
+            )?
+            .map(|row| {
+                Ok((
+                    row.get::<String>(1)?.unwrap(),
+                    row.get::<pg_sys::Oid>(2)?.unwrap(),
+                ))
+            })
+            .collect::<Result<_, _>>()
+            .map(Some)
+    })
+}
+
+/// Whether `relid` is `jsonschema.formats`.
+pub(super) fn is_formats_table(relid: pg_sys::Oid) -> bool {
+    relid == FORMATS_OID.get()
+}
+
+/// Forgets the loaded formats; they are read again on the next compile.
+pub(super) fn invalidate() {
+    REGISTERED.set(None);
+}
+
+/// Calls the `text -> boolean` checker function `checker` on `value`. A NULL
+/// result rejects the value.
+fn check(checker: &Function, value: &str) -> bool {
+    check_for_interrupts!();
+    checker
+        .call(&[value.into_datum().unwrap()])
+        .and_then(|result| unsafe { bool::from_datum(result, false) })
+        .unwrap_or(false)
+}
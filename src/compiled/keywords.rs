@@ -0,0 +1,132 @@
+//! Custom keywords implemented by SQL functions.
+//!
+//! Keywords registered in `jsonschema.keywords` are validated by calling their
+//! `(jsonb, jsonb) -> text` function with the keyword's value and the instance
+//! fragment; a non-NULL result is the error message. Changes to the table
+//! flush compiled validators the same way registry changes do (see
+//! [`super::registry`]).
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+    sync::{Arc, OnceLock},
+};
+
+use jsonschema::{Keyword, ValidationError, paths::Location};
+use pgrx::*;
+use serde_json::{Map, Value};
+
+use super::fmgr::Function;
+
+thread_local! {
+    /// Contents of `jsonschema.keywords`, loaded on first compile.
+    static REGISTERED: RefCell<Option<Rc<[(String, pg_sys::Oid)]>>> = const { RefCell::new(None) };
+    /// OID of `jsonschema.keywords`, resolved along with its contents.
+    static KEYWORDS_OID: Cell<pg_sys::Oid> = const { Cell::new(pg_sys::InvalidOid) };
+}
+
+/// Adds every keyword registered in `jsonschema.keywords` to `options`.
+pub(super) fn with_registered(
+    mut options: jsonschema::ValidationOptions<'_>,
+) -> jsonschema::ValidationOptions<'_> {
+    for (name, validator) in registered().iter() {
+        let oid = *validator;
+        // Looked up where the keyword is first used, and shared by every
+        // occurrence in the schema.
+        let function = OnceLock::new();
+        options = options.with_keyword(
+            name.as_str(),
+            move |_: &Map<String, Value>, value: &Value, _: Location| {
+                let validator = function.get_or_init(|| Arc::new(Function::new(oid)));
+                Ok(Box::new(SqlKeyword {
+                    validator: Arc::clone(validator),
+                    value: value.clone(),
+                }) as Box<dyn Keyword>)
+            },
+        );
+    }
+    options
+}
+
+fn registered() -> Rc<[(String, pg_sys::Oid)]> {
+    if let Some(keywords) = REGISTERED.with_borrow(Option::clone) {
+        return keywords;
+    }
+    match load().unwrap_or_else(|err| pgrx::error!("failed to load registered keywords: {err}")) {
+        Some(keywords) => {
+            REGISTERED.set(Some(Rc::clone(&keywords)));
+            keywords
+        }
+        // Not remembered, so the table is read once an upgrade creates it.
+        None => Rc::from([]),
+    }
+}
+
+/// Reads `jsonschema.keywords`, `None` if it doesn't exist, as in an
+/// installation not yet upgraded from 0.3.4.
+fn load() -> Result<Option<Rc<[(String, pg_sys::Oid)]>>, pgrx::spi::Error> {
+    let Some(oid) =
+        Spi::get_one::<pg_sys::Oid>("SELECT pg_catalog.to_regclass('jsonschema.keywords')::oid")?
+    else {
+        return Ok(None);
+    };
+    KEYWORDS_OID.set(oid);
+    Spi::connect(|client| {
+        client
+            .select(
+                "SELECT name, validator::oid FROM jsonschema.keywords",
+                None,
+                &[],
+            )?
+            .map(|row| {
+                Ok((
+                    row.get::<String>(1)?.unwrap(),
+                    row.get::<pg_sys::Oid>(2)?.unwrap(),
+                ))
+            })
+            .collect::<Result<_, _>>()
+            .map(Some)
+    })
+}
+
+/// Whether `relid` is `jsonschema.keywords`.
+pub(super) fn is_keywords_table(relid: pg_sys::Oid) -> bool {
+    relid == KEYWORDS_OID.get()
+}
+
+/// Forgets the loaded keywords; they are read again on the next compile.
+pub(super) fn invalidate() {
+    REGISTERED.set(None);
+}
+
+/// A registered keyword, with its value in the schema being compiled.
+struct SqlKeyword {
+    validator: Arc<Function>,
+    value: Value,
+}
+
+impl SqlKeyword {
+    /// Calls the validator function, returning its error message if any.
+    fn check(&self, instance: &Value) -> Option<String> {
+        check_for_interrupts!();
+        let args = [
+            JsonB(self.value.clone()).into_datum().unwrap(),
+            JsonB(instance.clone()).into_datum().unwrap(),
+        ];
+        self.validator
+            .call(&args)
+            .and_then(|result| unsafe { String::from_datum(result, false) })
+    }
+}
+
+impl Keyword for SqlKeyword {
+    fn validate<'i>(&self, instance: &'i Value) -> Result<(), ValidationError<'i>> {
+        match self.check(instance) {
+            Some(message) => Err(ValidationError::custom(message)),
+            None => Ok(()),
+        }
+    }
+
+    fn is_valid(&self, instance: &Value) -> bool {
+        self.check(instance).is_none()
+    }
+}
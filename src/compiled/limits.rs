@@ -0,0 +1,197 @@
+//! Resource limits for schemas from untrusted sources.
+//!
+//! All limits are checked when a `jsonschema` value is created: its size and
+//! nesting depth before it is compiled, the depth of `$ref` chains through
+//! `jsonschema.registry` and the time spent compiling while it is. Recompiling
+//! a stored value, e.g. after it was evicted from the cache, is not limited.
+//! Exceeding a limit raises `program_limit_exceeded` (SQLSTATE 54000), so that
+//! it can be told apart from an invalid schema.
+use std::{cell::RefCell, collections::HashMap, time::Instant};
+
+use pgrx::*;
+use serde_json::Value;
+
+use crate::guc;
+
+fn exceeded(message: String, setting: &str) -> ! {
+    pg_sys::panic::ErrorReport::new(
+        PgSqlErrorCode::ERRCODE_PROGRAM_LIMIT_EXCEEDED,
+        message,
+        function_name!(),
+    )
+    .set_hint(format!("The limit is set by pg_jsonschema.{setting}."))
+    .report(PgLogLevel::ERROR);
+    unreachable!()
+}
+
+/// The limit set by `setting`, `None` if it is disabled.
+fn limit(setting: &pgrx::GucSetting<i32>) -> Option<usize> {
+    usize::try_from(setting.get())
+        .ok()
+        .filter(|&limit| limit > 0)
+}
+
+/// Checks the length of a schema's text against `pg_jsonschema.max_schema_bytes`.
+pub(super) fn check_size(bytes: usize) {
+    if let Some(max) = limit(&guc::MAX_SCHEMA_BYTES)
+        && bytes > max
+    {
+        exceeded(
+            format!("JSON schema of {bytes} bytes exceeds the maximum of {max} bytes"),
+            "max_schema_bytes",
+        );
+    }
+}
+
+/// Checks the length of `schema`'s canonical form against
+/// `pg_jsonschema.max_schema_bytes`. It is measured as given, before the
+/// compile options are recorded in it.
+pub(super) fn check_canonical_size(schema: &Value) {
+    if limit(&guc::MAX_SCHEMA_BYTES).is_none() {
+        return;
+    }
+    let canonical = jsonschema::canonical::json::to_string(schema)
+        .unwrap_or_else(|err| pgrx::error!("failed to canonicalize JSON schema: {err}"));
+    check_size(canonical.len());
+}
+
+/// Checks how deeply objects and arrays nest in `schema` against
+/// `pg_jsonschema.max_schema_depth`.
+pub(super) fn check_depth(schema: &Value) {
+    let Some(max) = limit(&guc::MAX_SCHEMA_DEPTH) else {
+        return;
+    };
+    let mut stack = vec![(schema, 1)];
+    while let Some((value, depth)) = stack.pop() {
+        match value {
+            Value::Object(_) | Value::Array(_) if depth > max => exceeded(
+                format!("JSON schema nesting depth exceeds the maximum of {max}"),
+                "max_schema_depth",
+            ),
+            Value::Object(members) => {
+                stack.extend(members.values().map(|child| (child, depth + 1)))
+            }
+            Value::Array(items) => stack.extend(items.iter().map(|child| (child, depth + 1))),
+            _ => {}
+        }
+    }
+}
+
+/// State of the compilation in progress.
+struct Compilation {
+    started: Instant,
+    /// Length of the `$ref` chain leading to each registry URI seen so far.
+    ref_depths: HashMap<String, usize>,
+}
+
+thread_local! {
+    static COMPILATION: RefCell<Option<Compilation>> = const { RefCell::new(None) };
+}
+
+/// Restores the enclosing compilation's state, also when compiling fails.
+struct Scope(Option<Compilation>);
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        COMPILATION.set(self.0.take());
+    }
+}
+
+/// Whether the `$ref` depth or compile time limit is set. A cached validator
+/// doesn't tell how deep its `$ref`s went or how long it took to compile, so
+/// new values are then compiled even if they are cached.
+pub(super) fn compile_limited() -> bool {
+    limit(&guc::MAX_REF_DEPTH).is_some() || limit(&guc::MAX_COMPILE_MS).is_some()
+}
+
+/// Runs `compile`, enforcing the `$ref` depth and compile time limits. Outside
+/// of it, [`check_deadline`] and [`enter_ref`] check nothing.
+pub(super) fn compiling<T>(compile: impl FnOnce() -> T) -> T {
+    let _scope = Scope(COMPILATION.replace(Some(Compilation {
+        started: Instant::now(),
+        ref_depths: HashMap::new(),
+    })));
+    let compiled = compile();
+    check_deadline();
+    compiled
+}
+
+/// Checks the time spent compiling against `pg_jsonschema.max_compile_ms`.
+///
+/// A running compilation can only be stopped where it calls back into this
+/// extension, i.e. when it retrieves a registered schema, and once it is done.
+/// A schema without registry references therefore pays its full compile cost
+/// before the limit is noticed.
+pub(super) fn check_deadline() {
+    let Some(max) = limit(&guc::MAX_COMPILE_MS) else {
+        return;
+    };
+    let elapsed = COMPILATION.with_borrow(|compilation| {
+        compilation
+            .as_ref()
+            .map(|compilation| compilation.started.elapsed().as_millis())
+    });
+    if let Some(elapsed) = elapsed
+        && elapsed > max as u128
+    {
+        exceeded(
+            format!("compiling JSON schema took {elapsed} ms, more than the maximum of {max} ms"),
+            "max_compile_ms",
+        );
+    }
+}
+
+/// Checks the length of the `$ref` chain leading to `uri` against
+/// `pg_jsonschema.max_ref_depth`, returning it.
+pub(super) fn enter_ref(uri: &jsonschema::Uri<String>) -> usize {
+    check_deadline();
+    // URIs not referenced by a retrieved schema are referenced by the root.
+    let depth = COMPILATION.with_borrow(|compilation| {
+        compilation
+            .as_ref()
+            .and_then(|compilation| compilation.ref_depths.get(uri.as_str()).copied())
+            .unwrap_or(1)
+    });
+    if let Some(max) = limit(&guc::MAX_REF_DEPTH)
+        && depth > max
+    {
+        exceeded(
+            format!(
+                "$ref chain to \"{}\" exceeds the maximum depth of {max}",
+                uri.as_str()
+            ),
+            "max_ref_depth",
+        );
+    }
+    depth
+}
+
+/// Records the `$ref`s of `schema`, retrieved from `uri` at `depth`, as one
+/// level deeper.
+pub(super) fn record_refs(uri: &jsonschema::Uri<String>, depth: usize, schema: &Value) {
+    let base = uri.borrow();
+    COMPILATION.with_borrow_mut(|compilation| {
+        let Some(compilation) = compilation else {
+            return;
+        };
+        let mut stack = vec![schema];
+        while let Some(value) = stack.pop() {
+            match value {
+                Value::Object(members) => {
+                    if let Some(Value::String(reference)) = members.get("$ref")
+                        && let Ok(mut target) = jsonschema::uri::resolve_against(&base, reference)
+                    {
+                        target.set_fragment(None);
+                        compilation
+                            .ref_depths
+                            .entry(target.into_string())
+                            .or_insert(depth + 1);
+                    }
+                    stack.extend(members.values());
+                }
+                Value::Array(items) => stack.extend(items),
+                _ => {}
+            }
+        }
+    });
+}
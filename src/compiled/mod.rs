@@ -1,12 +1,26 @@
-mod cache;
+pub(crate) mod cache;
 mod callsite;
+mod datum;
+pub(crate) mod draft;
+mod fmgr;
+pub(crate) mod formats;
+mod keywords;
+mod limits;
+mod patterns;
+mod references;
+mod registry;
 
-use std::{ffi::CStr, sync::Arc};
+use std::{borrow::Cow, ffi::CStr, sync::Arc};
 
 use pgrx::*;
 use serde_json::Value;
 
-pub(crate) use callsite::fn_extra_get_or_compile;
+pub(crate) use callsite::{
+    fn_extra_get_or_compile, fn_extra_get_or_compile_arg, fn_extra_get_or_compile_value,
+    get_or_compile,
+};
+pub(crate) use datum::Fingerprint;
+pub(crate) use registry::schema_by_name;
 
 /// JSON schema is stored as its canonical JSON string.
 ///
@@ -14,33 +28,98 @@ pub(crate) use callsite::fn_extra_get_or_compile;
 /// representation, maximising cache hits. The compiled [`jsonschema::Validator`]
 /// is held in a two-level cache: a per-callsite slot in `fn_extra`  and a
 /// bounded backend-local LRU.
-#[derive(
-    PostgresType,
-    PostgresEq,
-    PostgresHash,
-    PartialEq,
-    Eq,
-    Hash,
-    serde::Serialize,
-    serde::Deserialize,
-)]
+///
+/// Values are stored with a fingerprint of the canonical string in front of it
+/// (see [`datum`]), which equality and hashing compare instead of the string.
+#[derive(Clone, PostgresType, PartialEq, Eq, Hash)]
 #[inoutfuncs]
+#[bikeshed_postgres_type_manually_impl_from_into_datum]
 pub struct JsonSchema {
     pub value: String,
+    fingerprint: Fingerprint,
+}
+
+/// Backend-wide setup, called from `_PG_init`.
+pub(crate) fn init() {
+    registry::register_invalidation_callback();
 }
 
 impl JsonSchema {
+    /// Wraps an already canonical schema string.
+    fn from_canonical(value: String) -> Self {
+        let fingerprint = datum::fingerprint(&value);
+        Self { value, fingerprint }
+    }
+
+    /// Wraps a schema stored by a version that didn't record compile options.
+    ///
+    /// They are recorded as that version compiled every schema, with the
+    /// default draft and the draft's own format assertion, so that the value
+    /// gets the same canonical text and fingerprint as the schema input today.
+    fn from_legacy(text: &str) -> Self {
+        let value: Value = serde_json::from_str(text)
+            .unwrap_or_else(|err| pgrx::error!("corrupt jsonschema value: {err}"));
+        let value = match value.as_object() {
+            Some(object) => with_options(
+                object,
+                (!object.contains_key("$schema")).then(jsonschema::Draft::default),
+                (!object.contains_key(formats::KEYWORD)).then_some(Value::Null),
+            )
+            .map_or(Cow::Borrowed(&value), Cow::Owned),
+            None => Cow::Borrowed(&value),
+        };
+        let canonical = jsonschema::canonical::json::to_string(&value)
+            .unwrap_or_else(|err| pgrx::error!("failed to canonicalize JSON schema: {err}"));
+        Self::from_canonical(canonical)
+    }
+
+    pub(crate) fn fingerprint(&self) -> &Fingerprint {
+        &self.fingerprint
+    }
+
     /// Canonicalize, compile, and cache a JSON schema value.
     pub(crate) fn compile(value: Value) -> Self {
+        Self::compile_with_draft(value, None)
+    }
+
+    /// Like [`Self::compile`], recording `draft` as the schema's `$schema`.
+    pub(crate) fn compile_with_draft(value: Value, draft: Option<jsonschema::Draft>) -> Self {
+        Self::try_compile_with_draft(&value, draft)
+            .unwrap_or_else(|err| pgrx::error!("invalid JSON schema: {err}"))
+    }
+
+    /// Like [`Self::compile`], but hands schema errors back to the caller.
+    pub(crate) fn try_compile(value: &Value) -> Result<Self, jsonschema::ValidationError<'static>> {
+        Self::try_compile_with_draft(value, None)
+    }
+
+    fn try_compile_with_draft(
+        value: &Value,
+        draft: Option<jsonschema::Draft>,
+    ) -> Result<Self, jsonschema::ValidationError<'static>> {
+        limits::check_depth(value);
+        limits::check_canonical_size(value);
+        let value = record_options(value, draft);
         let canonical = jsonschema::canonical::json::to_string(&value)
             .unwrap_or_else(|err| pgrx::error!("failed to canonicalize JSON schema: {err}"));
-        cache::get_or_insert(&canonical, || compile_impl(&value, "invalid JSON schema"));
-        Self { value: canonical }
+        let schema = Self::from_canonical(canonical);
+        // Only new values are held to the compile limits: stored ones recompile
+        // unrestricted, so they stay usable after the limits are lowered.
+        let compile = || limits::compiling(|| try_compile_impl(&value));
+        if limits::compile_limited() {
+            cache::try_insert(&schema, compile)?;
+        } else {
+            cache::try_get_or_insert(&schema, compile)?;
+        }
+        Ok(schema)
     }
 }
 
 impl pgrx::inoutfuncs::InOutFuncs for JsonSchema {
     fn input(input: &CStr) -> Self {
+        // Rejects oversized input before parsing it; the canonical form is
+        // checked once it is known.
+        limits::check_size(input.to_bytes().len());
         let value: Value = serde_json::from_slice(input.to_bytes())
             .unwrap_or_else(|err| pgrx::error!("invalid JSON: {err}"));
         Self::compile(value)
@@ -51,11 +130,174 @@ impl pgrx::inoutfuncs::InOutFuncs for JsonSchema {
     }
 }
 
-fn compile_impl(value: &Value, error_prefix: &str) -> Arc<jsonschema::Validator> {
-    Arc::new(
-        jsonschema::validator_for(value)
-            .unwrap_or_else(|err| pgrx::error!("{error_prefix}: {err}")),
+/// Records the compile options that `value` doesn't spell out itself, so they
+/// become part of its canonical form. Boolean schemas are left untouched as
+/// they have nowhere to record them.
+fn record_options(value: &Value, draft: Option<jsonschema::Draft>) -> Cow<'_, Value> {
+    let Some(object) = value.as_object() else {
+        return Cow::Borrowed(value);
+    };
+    with_options(
+        object,
+        draft::resolve(object, draft),
+        formats::resolve(object),
     )
+    .map_or(Cow::Borrowed(value), Cow::Owned)
+}
+
+/// `object` with `draft` and `validate_formats` recorded, `None` if there is
+/// nothing to record.
+fn with_options(
+    object: &serde_json::Map<String, Value>,
+    draft: Option<jsonschema::Draft>,
+    validate_formats: Option<Value>,
+) -> Option<Value> {
+    if draft.is_none() && validate_formats.is_none() {
+        return None;
+    }
+    let mut object = object.clone();
+    if let Some(draft) = draft {
+        object.insert("$schema".into(), draft::meta_schema_uri(draft).into());
+    }
+    if let Some(validate_formats) = validate_formats {
+        object.insert(formats::KEYWORD.into(), validate_formats);
+    }
+    Some(Value::Object(object))
+}
+
+#[pg_operator(immutable, parallel_safe, requires = [JsonSchema])]
+#[opname(=)]
+#[commutator(=)]
+#[negator(<>)]
+#[restrict(eqsel)]
+#[join(eqjoinsel)]
+#[merges]
+#[hashes]
+fn jsonschema_eq(left: datum::SchemaFingerprint, right: datum::SchemaFingerprint) -> bool {
+    left.0 == right.0
+}
+
+#[pg_operator(immutable, parallel_safe, requires = [JsonSchema])]
+#[opname(<>)]
+#[commutator(<>)]
+#[negator(=)]
+#[restrict(neqsel)]
+#[join(neqjoinsel)]
+fn jsonschema_ne(left: datum::SchemaFingerprint, right: datum::SchemaFingerprint) -> bool {
+    left.0 != right.0
+}
+
+/// The schema's fingerprint, read without detoasting the schema itself.
+#[pg_extern(immutable, strict, parallel_safe, requires = [JsonSchema])]
+fn jsonschema_fingerprint(schema: datum::SchemaFingerprint) -> Vec<u8> {
+    schema.0.to_vec()
+}
+
+#[pg_extern(immutable, parallel_safe, requires = [JsonSchema])]
+fn jsonschema_hash(value: datum::SchemaFingerprint) -> i32 {
+    let [a, b, c, d, ..] = value.0;
+    i32::from_le_bytes([a, b, c, d])
+}
+
+pgrx::extension_sql!(
+    r#"
+    CREATE OPERATOR FAMILY jsonschema_hash_ops USING hash;
+    CREATE OPERATOR CLASS jsonschema_hash_ops DEFAULT FOR TYPE jsonschema USING hash FAMILY jsonschema_hash_ops AS
+        OPERATOR    1   =  (jsonschema, jsonschema),
+        FUNCTION    1   jsonschema_hash(jsonschema);
+    "#,
+    name = "jsonschema_hash_ops",
+    requires = [jsonschema_eq, jsonschema_hash],
+);
+
+/// Version byte leading the binary representation of `jsonschema`.
+const BINARY_FORMAT_VERSION: u8 = 1;
+
+/// Binary output: a version byte followed by the canonical schema text in the
+/// client encoding, like `jsonb_send`.
+#[pg_extern(immutable, strict, parallel_safe)]
+fn jsonschema_send(schema: JsonSchema) -> Vec<u8> {
+    let text = convert_encoding(schema.value.as_bytes(), |s, len| unsafe {
+        pg_sys::pg_server_to_client(s, len)
+    });
+    let mut bytes = Vec::with_capacity(text.len() + 1);
+    bytes.push(BINARY_FORMAT_VERSION);
+    bytes.extend_from_slice(&text);
+    bytes
+}
+
+/// Binary input, the inverse of [`jsonschema_send`]. The schema is compiled,
+/// so invalid schemas are rejected as they are by text input. Values written by
+/// [`jsonschema_send`] already record their draft and format assertion, so the
+/// settings of the receiving session leave them unchanged.
+#[pg_extern(immutable, strict, parallel_safe)]
+fn jsonschema_recv(mut internal: pgrx::datum::Internal) -> JsonSchema {
+    let buf = unsafe { internal.get_mut::<pg_sys::StringInfoData>() }
+        .unwrap_or_else(|| pgrx::error!("jsonschema_recv called without a buffer"));
+    let bytes = unsafe {
+        std::slice::from_raw_parts(
+            buf.data.add(buf.cursor as usize) as *const u8,
+            (buf.len - buf.cursor) as usize,
+        )
+    };
+    buf.cursor = buf.len;
+    let Some((&version, text)) = bytes.split_first() else {
+        pgrx::error!("invalid jsonschema binary representation: no data");
+    };
+    if version != BINARY_FORMAT_VERSION {
+        pgrx::error!("unsupported jsonschema binary format version {version}");
+    }
+    let text = convert_encoding(text, |s, len| unsafe {
+        pg_sys::pg_client_to_server(s, len)
+    });
+    let value: Value =
+        serde_json::from_slice(&text).unwrap_or_else(|err| pgrx::error!("invalid JSON: {err}"));
+    JsonSchema::compile(value)
+}
+
+pgrx::extension_sql!(
+    r#"
+    ALTER TYPE jsonschema SET (SEND = jsonschema_send, RECEIVE = jsonschema_recv);
+    "#,
+    name = "jsonschema_binary_io",
+    requires = [JsonSchema, jsonschema_send, jsonschema_recv],
+);
+
+/// Applies `pg_server_to_client` or `pg_client_to_server` to `bytes`.
+fn convert_encoding(
+    bytes: &[u8],
+    convert: impl FnOnce(*const std::ffi::c_char, i32) -> *mut std::ffi::c_char,
+) -> Cow<'_, [u8]> {
+    let len = i32::try_from(bytes.len())
+        .unwrap_or_else(|_| pgrx::error!("jsonschema value is too large"));
+    unsafe {
+        let converted = convert(bytes.as_ptr().cast(), len);
+        // The input is returned as is when no conversion is needed; otherwise
+        // the result is a palloc'd, NUL-terminated string.
+        if converted.cast_const() == bytes.as_ptr().cast() {
+            Cow::Borrowed(bytes)
+        } else {
+            Cow::Owned(CStr::from_ptr(converted).to_bytes().to_vec())
+        }
+    }
+}
+
+fn try_compile_impl(
+    value: &Value,
+) -> Result<Arc<jsonschema::Validator>, jsonschema::ValidationError<'static>> {
+    let options = jsonschema::options()
+        .with_retriever(registry::RegistryRetriever)
+        .with_keyword(references::KEYWORD, references::factory);
+    let mut options =
+        patterns::with_configured(keywords::with_registered(formats::with_registered(options)));
+    if let Some(validate_formats) = formats::recorded(value) {
+        options = options.should_validate_formats(validate_formats);
+    }
+    options.build(value).map(Arc::new)
+}
+
+fn compile_impl(value: &Value, error_prefix: &str) -> Arc<jsonschema::Validator> {
+    try_compile_impl(value).unwrap_or_else(|err| pgrx::error!("{error_prefix}: {err}"))
 }
 
 pub(super) fn compile_from_str(schema: &str) -> Arc<jsonschema::Validator> {
@@ -0,0 +1,62 @@
+//! Regular expression engine and limits for `pattern` and `patternProperties`.
+//!
+//! Unlike the draft and format assertion, these settings are not recorded in
+//! the schema: they protect the server from the schemas it is given, so the
+//! backend's own settings apply to every schema it compiles. Validators
+//! compiled under other settings are dropped when they change (see
+//! [`super::cache`]).
+use jsonschema::PatternOptions;
+
+use crate::guc::{self, RegexEngine};
+
+/// The pattern settings in effect.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(super) struct Settings {
+    engine: RegexEngine,
+    size_limit: i32,
+    backtrack_limit: i32,
+}
+
+impl Settings {
+    pub(super) fn current() -> Self {
+        Self {
+            engine: guc::REGEX_ENGINE.get(),
+            size_limit: guc::REGEX_SIZE_LIMIT.get(),
+            backtrack_limit: guc::REGEX_BACKTRACK_LIMIT.get(),
+        }
+    }
+}
+
+/// Applies the current pattern settings to `options`.
+pub(super) fn with_configured(
+    options: jsonschema::ValidationOptions<'_>,
+) -> jsonschema::ValidationOptions<'_> {
+    let Settings {
+        engine,
+        size_limit,
+        backtrack_limit,
+    } = Settings::current();
+    let size_limit = usize::try_from(size_limit).ok().filter(|&limit| limit > 0);
+    let backtrack_limit = usize::try_from(backtrack_limit)
+        .ok()
+        .filter(|&limit| limit > 0);
+    match engine {
+        RegexEngine::Fancy => {
+            let mut patterns = PatternOptions::fancy_regex();
+            if let Some(limit) = size_limit {
+                patterns = patterns.size_limit(limit);
+            }
+            if let Some(limit) = backtrack_limit {
+                patterns = patterns.backtrack_limit(limit);
+            }
+            options.with_pattern_options(patterns)
+        }
+        RegexEngine::Regex => {
+            let mut patterns = PatternOptions::regex();
+            if let Some(limit) = size_limit {
+                patterns = patterns.size_limit(limit);
+            }
+            options.with_pattern_options(patterns)
+        }
+    }
+}
@@ -0,0 +1,148 @@
+//! The built-in `x-pg-references` keyword.
+//!
+//! `{"x-pg-references": {"table": "public.customers", "column": "id"}}` asserts
+//! that the instance is present in the given column, like a foreign key inside
+//! a document. The table and column are resolved once, when the schema is
+//! compiled; every validation then runs an SPI lookup against the table.
+//!
+//! Validation results then depend on the table's contents, even though the
+//! validation functions are declared `IMMUTABLE`; the README warns about this.
+use jsonschema::{Keyword, ValidationError, paths::Location};
+use pgrx::*;
+use serde_json::{Map, Value};
+
+pub(super) const KEYWORD: &str = "x-pg-references";
+
+/// Compiles an `x-pg-references` keyword, resolving its table and column.
+pub(super) fn factory<'a>(
+    _: &'a Map<String, Value>,
+    value: &'a Value,
+    _: Location,
+) -> Result<Box<dyn Keyword>, ValidationError<'a>> {
+    let (Some(table), Some(column)) = (
+        value.get("table").and_then(Value::as_str),
+        value.get("column").and_then(Value::as_str),
+    ) else {
+        return Err(ValidationError::custom(format!(
+            "{KEYWORD} must be an object with string \"table\" and \"column\" members"
+        )));
+    };
+    let query = resolve(table, column)
+        .unwrap_or_else(|err| pgrx::error!("failed to resolve {KEYWORD}: {err}"))
+        .ok_or_else(|| {
+            ValidationError::custom(format!(
+                "{KEYWORD}: column \"{column}\" of table \"{table}\" does not exist"
+            ))
+        })?;
+    Ok(Box::new(References {
+        query,
+        target: format!("{table}({column})"),
+    }))
+}
+
+/// Builds the lookup query for `column` of `table`, `None` if there is no such
+/// table or column.
+///
+/// Identifiers come back quoted from the catalog, with the table always
+/// schema-qualified so that the query keeps reading the table resolved here
+/// whatever the `search_path` at validation time. The parameter is cast to the
+/// column's type, without its typmod so that values are not truncated or
+/// rounded into a match, letting the lookup use an index.
+fn resolve(table: &str, column: &str) -> Result<Option<String>, pgrx::spi::Error> {
+    let found = Spi::get_three_with_args::<String, String, String>(
+        r#"
+        SELECT quote_ident(n.nspname) || '.' || quote_ident(c.relname),
+               quote_ident(a.attname), format_type(a.atttypid, NULL)
+        FROM pg_catalog.pg_attribute a
+        JOIN pg_catalog.pg_class c ON c.oid = a.attrelid
+        JOIN pg_catalog.pg_namespace n ON n.oid = c.relnamespace
+        WHERE a.attrelid = pg_catalog.to_regclass($1) AND a.attname = $2
+          AND a.attnum > 0 AND NOT a.attisdropped
+        "#,
+        &[table.into(), column.into()],
+    );
+    match found {
+        Ok((Some(table), Some(column), Some(ty))) => Ok(Some(format!(
+            "SELECT EXISTS (SELECT FROM {table} WHERE {column} = $1::{ty})"
+        ))),
+        Ok(_) | Err(pgrx::spi::Error::InvalidPosition) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+struct References {
+    query: String,
+    /// `table(column)` as written in the schema, for error messages.
+    target: String,
+}
+
+impl References {
+    /// Whether `key` is present in the column. Values that can't be cast to
+    /// the column's type are not.
+    fn exists(&self, key: &str) -> bool {
+        check_for_interrupts!();
+        unless_data_exception(|| {
+            Spi::get_one_with_args::<bool>(&self.query, &[key.into()])
+                .unwrap_or_else(|err| pgrx::error!("{KEYWORD} lookup failed: {err}"))
+                .unwrap_or(false)
+        })
+        .unwrap_or(false)
+    }
+}
+
+/// Runs `lookup` in a subtransaction, returning `None` if it raised a data
+/// exception (SQLSTATE class 22), such as `invalid_text_representation` or
+/// `numeric_value_out_of_range` for a value the column's type won't accept.
+/// Other errors are raised again.
+fn unless_data_exception<R>(lookup: impl FnOnce() -> R + std::panic::UnwindSafe) -> Option<R> {
+    unsafe {
+        let context = pg_sys::CurrentMemoryContext;
+        let owner = pg_sys::CurrentResourceOwner;
+        pg_sys::BeginInternalSubTransaction(std::ptr::null());
+        pg_sys::MemoryContextSwitchTo(context);
+        PgTryBuilder::new(|| {
+            let result = lookup();
+            pg_sys::ReleaseCurrentSubTransaction();
+            pg_sys::MemoryContextSwitchTo(context);
+            pg_sys::CurrentResourceOwner = owner;
+            Some(result)
+        })
+        .catch_others(|error| {
+            pg_sys::RollbackAndReleaseCurrentSubTransaction();
+            pg_sys::MemoryContextSwitchTo(context);
+            pg_sys::CurrentResourceOwner = owner;
+            match &error {
+                pg_sys::panic::CaughtError::PostgresError(report)
+                    if report.sql_error_code() as isize & 0xFFF
+                        == PgSqlErrorCode::ERRCODE_DATA_EXCEPTION as isize =>
+                {
+                    None
+                }
+                _ => error.rethrow(),
+            }
+        })
+        .execute()
+    }
+}
+
+impl Keyword for References {
+    fn validate<'i>(&self, instance: &'i Value) -> Result<(), ValidationError<'i>> {
+        if self.is_valid(instance) {
+            Ok(())
+        } else {
+            Err(ValidationError::custom(format!(
+                "{instance} is not present in {}",
+                self.target
+            )))
+        }
+    }
+
+    fn is_valid(&self, instance: &Value) -> bool {
+        match instance {
+            Value::String(key) => self.exists(key),
+            Value::Number(key) => self.exists(&key.to_string()),
+            Value::Bool(key) => self.exists(&key.to_string()),
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,147 @@
+//! `$ref` resolution and named lookups against the extension-owned
+//! `jsonschema.registry` table.
+//!
+//! Every compiled validator may depend on registry contents, either by name or
+//! through a `$ref`, and on the formats and keywords in `jsonschema.formats` and
+//! `jsonschema.keywords`. A statement-level trigger on each of these tables
+//! queues a relcache invalidation for it, which every backend processes at its
+//! next transaction (or sooner) and answers by flushing its caches.
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::Rc,
+};
+
+use pgrx::*;
+use serde_json::Value;
+
+use super::{JsonSchema, cache, formats, keywords, limits};
+use crate::guc::{self, DefaultDraft};
+
+thread_local! {
+    /// Registered schemas already fetched by this backend, keyed by URI and
+    /// the settings they were compiled under.
+    static NAMED: RefCell<HashMap<NamedKey, Rc<JsonSchema>>> = RefCell::new(HashMap::new());
+    /// OID of `jsonschema.registry`, resolved on first read.
+    static REGISTRY_OID: Cell<pg_sys::Oid> = const { Cell::new(pg_sys::InvalidOid) };
+}
+
+/// A registered schema's URI, with `pg_jsonschema.default_draft` and
+/// `pg_jsonschema.validate_formats`: registry rows don't record them, so the
+/// same row compiles differently under other settings.
+type NamedKey = (String, DefaultDraft, bool);
+
+#[pg_guard]
+unsafe extern "C-unwind" {
+    // utils/inval.h is not part of the generated bindings.
+    fn CacheRegisterRelcacheCallback(
+        func: Option<unsafe extern "C-unwind" fn(pg_sys::Datum, pg_sys::Oid)>,
+        arg: pg_sys::Datum,
+    );
+    fn CacheInvalidateRelcacheByRelid(relid: pg_sys::Oid);
+}
+
+/// Registers the relcache callback that flushes caches on registry changes.
+/// Must be called once per backend, from `_PG_init`.
+pub(super) fn register_invalidation_callback() {
+    unsafe {
+        CacheRegisterRelcacheCallback(
+            Some(invalidate_on_registry_change),
+            pg_sys::Datum::from(0usize),
+        );
+    }
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn invalidate_on_registry_change(_arg: pg_sys::Datum, relid: pg_sys::Oid) {
+    // InvalidOid means "all relations", e.g. after a sinval queue overflow.
+    if relid == pg_sys::InvalidOid
+        || relid == REGISTRY_OID.get()
+        || formats::is_formats_table(relid)
+        || keywords::is_keywords_table(relid)
+    {
+        NAMED.with_borrow_mut(|named| named.clear());
+        formats::invalidate();
+        keywords::invalidate();
+        cache::invalidate();
+    }
+}
+
+/// Statement-level trigger on the `jsonschema` tables that invalidates the
+/// compiled validators of every backend once the change commits.
+#[pg_trigger]
+fn jsonschema_registry_invalidate<'a>(
+    trigger: &'a PgTrigger<'a>,
+) -> Result<Option<PgHeapTuple<'a, AllocatedByPostgres>>, PgTriggerError> {
+    let relid = trigger.relid()?;
+    unsafe { CacheInvalidateRelcacheByRelid(relid) };
+    Ok(None)
+}
+
+/// Returns the registered schema for `uri`, fetching and compiling it on first use.
+///
+/// Compilation goes through [`JsonSchema::compile`], so the validator lands in
+/// the backend-local LRU under its canonical form and is shared with any
+/// literal use of the same schema.
+pub(crate) fn schema_by_name(uri: &str) -> Rc<JsonSchema> {
+    let key = (
+        uri.to_owned(),
+        guc::DEFAULT_DRAFT.get(),
+        guc::VALIDATE_FORMATS.get(),
+    );
+    if let Some(schema) = NAMED.with_borrow(|named| named.get(&key).cloned()) {
+        return schema;
+    }
+    let value = fetch(uri)
+        .unwrap_or_else(|err| pgrx::error!("failed to look up registered schema: {err}"))
+        .unwrap_or_else(|| {
+            pgrx::error!("schema \"{uri}\" is not registered in jsonschema.registry")
+        });
+    let schema = Rc::new(JsonSchema::compile(value));
+    NAMED.with_borrow_mut(|named| named.insert(key, Rc::clone(&schema)));
+    schema
+}
+
+fn fetch(uri: &str) -> Result<Option<Value>, pgrx::spi::Error> {
+    if REGISTRY_OID.get() == pg_sys::InvalidOid {
+        let oid = Spi::get_one::<pg_sys::Oid>("SELECT 'jsonschema.registry'::regclass::oid")?;
+        REGISTRY_OID.set(oid.unwrap_or(pg_sys::InvalidOid));
+    }
+    let schema = Spi::get_one_with_args::<JsonB>(
+        "SELECT schema FROM jsonschema.registry WHERE uri = $1",
+        &[uri.into()],
+    );
+    match schema {
+        Ok(schema) => Ok(schema.map(|JsonB(value)| value)),
+        // No row: the URI is not registered.
+        Err(pgrx::spi::Error::InvalidPosition) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Resolves external `$ref` URIs by looking them up in `jsonschema.registry`.
+///
+/// Lookups run through SPI while the referencing schema is being compiled, so
+/// a registered schema is only read again when a validator is recompiled.
+pub(super) struct RegistryRetriever;
+
+impl jsonschema::Retrieve for RegistryRetriever {
+    fn retrieve(
+        &self,
+        uri: &jsonschema::Uri<String>,
+    ) -> Result<Value, Box<dyn std::error::Error + Send + Sync>> {
+        check_for_interrupts!();
+        let depth = limits::enter_ref(uri);
+        match fetch(uri.as_str())? {
+            Some(value) => {
+                limits::record_refs(uri, depth, &value);
+                Ok(value)
+            }
+            None => Err(format!(
+                "schema \"{}\" is not registered in jsonschema.registry",
+                uri.as_str()
+            )
+            .into()),
+        }
+    }
+}
@@ -0,0 +1,181 @@
+//! Configuration parameters, registered from `_PG_init`.
+use pgrx::{GucContext, GucFlags, GucRegistry, GucSetting, PostgresGucEnum};
+
+/// `pg_jsonschema.cache_size`: maximum number of compiled validators per backend.
+pub(crate) static CACHE_SIZE: GucSetting<i32> = GucSetting::<i32>::new(128);
+
+/// `pg_jsonschema.cache_max_bytes`: cap on the canonical schema bytes cached per
+/// backend, `0` for no limit.
+pub(crate) static CACHE_MAX_BYTES: GucSetting<i32> = GucSetting::<i32>::new(0);
+
+/// Draft assumed for schemas that don't declare `$schema`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PostgresGucEnum)]
+pub(crate) enum DefaultDraft {
+    /// The `jsonschema` crate's default draft, currently 2020-12.
+    #[name = c"auto"]
+    Auto,
+    #[name = c"4"]
+    Draft4,
+    #[name = c"6"]
+    Draft6,
+    #[name = c"7"]
+    Draft7,
+    #[name = c"2019-09"]
+    Draft201909,
+    #[name = c"2020-12"]
+    Draft202012,
+}
+
+/// `pg_jsonschema.default_draft`: draft recorded for schemas without `$schema`.
+pub(crate) static DEFAULT_DRAFT: GucSetting<DefaultDraft> =
+    GucSetting::<DefaultDraft>::new(DefaultDraft::Auto);
+
+/// `pg_jsonschema.validate_formats`: assert `format` in schemas that don't say
+/// otherwise.
+pub(crate) static VALIDATE_FORMATS: GucSetting<bool> = GucSetting::<bool>::new(false);
+
+/// Regular expression engine for `pattern` and `patternProperties`.
+#[derive(Clone, Copy, PartialEq, Eq, PostgresGucEnum)]
+pub(crate) enum RegexEngine {
+    /// `fancy-regex`: supports look-around and backreferences, but may backtrack.
+    #[name = c"fancy"]
+    Fancy,
+    /// `regex`: runs in linear time, without look-around or backreferences.
+    #[name = c"regex"]
+    Regex,
+}
+
+/// `pg_jsonschema.regex_engine`: engine patterns are compiled with.
+pub(crate) static REGEX_ENGINE: GucSetting<RegexEngine> =
+    GucSetting::<RegexEngine>::new(RegexEngine::Fancy);
+
+/// `pg_jsonschema.regex_size_limit`: cap on the compiled size of a pattern, `0`
+/// for the engine's default.
+pub(crate) static REGEX_SIZE_LIMIT: GucSetting<i32> = GucSetting::<i32>::new(0);
+
+/// `pg_jsonschema.regex_backtrack_limit`: backtracking steps a `fancy` match may
+/// take, `0` for the engine's default.
+pub(crate) static REGEX_BACKTRACK_LIMIT: GucSetting<i32> = GucSetting::<i32>::new(0);
+
+/// `pg_jsonschema.max_schema_bytes`: cap on the length of a schema's text, `0`
+/// for no limit.
+pub(crate) static MAX_SCHEMA_BYTES: GucSetting<i32> = GucSetting::<i32>::new(0);
+
+/// `pg_jsonschema.max_schema_depth`: cap on the nesting of objects and arrays in
+/// a schema, `0` for no limit.
+pub(crate) static MAX_SCHEMA_DEPTH: GucSetting<i32> = GucSetting::<i32>::new(0);
+
+/// `pg_jsonschema.max_ref_depth`: cap on the length of `$ref` chains through the
+/// registry, `0` for no limit.
+pub(crate) static MAX_REF_DEPTH: GucSetting<i32> = GucSetting::<i32>::new(0);
+
+/// `pg_jsonschema.max_compile_ms`: cap on the time spent compiling a schema, `0`
+/// for no limit.
+pub(crate) static MAX_COMPILE_MS: GucSetting<i32> = GucSetting::<i32>::new(0);
+
+pub(crate) fn init() {
+    GucRegistry::define_int_guc(
+        c"pg_jsonschema.cache_size",
+        c"Maximum number of compiled schema validators cached per backend.",
+        c"Least recently used validators are evicted once the limit is reached.",
+        &CACHE_SIZE,
+        1,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        c"pg_jsonschema.cache_max_bytes",
+        c"Maximum total size of the schemas whose validators are cached per backend.",
+        c"Measured as the length of each canonical schema. Zero disables the limit.",
+        &CACHE_MAX_BYTES,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::UNIT_BYTE,
+    );
+    GucRegistry::define_enum_guc(
+        c"pg_jsonschema.default_draft",
+        c"JSON Schema draft used for schemas that don't declare $schema.",
+        c"The draft is recorded in the schema as its $schema when it is compiled.",
+        &DEFAULT_DRAFT,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_bool_guc(
+        c"pg_jsonschema.validate_formats",
+        c"Assert the format keyword in schemas that don't set x-pg-validate-formats.",
+        c"The setting is recorded in the schema when it is compiled.",
+        &VALIDATE_FORMATS,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_enum_guc(
+        c"pg_jsonschema.regex_engine",
+        c"Regular expression engine for pattern and patternProperties.",
+        c"\"regex\" matches in linear time but rejects look-around and backreferences.",
+        &REGEX_ENGINE,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        c"pg_jsonschema.regex_size_limit",
+        c"Maximum compiled size of a pattern.",
+        c"Schemas with larger patterns are rejected. Zero uses the engine's default.",
+        &REGEX_SIZE_LIMIT,
+        0,
+        i32::MAX,
+        GucContext::Suset,
+        GucFlags::UNIT_BYTE,
+    );
+    GucRegistry::define_int_guc(
+        c"pg_jsonschema.regex_backtrack_limit",
+        c"Maximum backtracking steps of a pattern match with the fancy engine.",
+        c"A match exceeding it fails. Zero uses the engine's default of one million.",
+        &REGEX_BACKTRACK_LIMIT,
+        0,
+        i32::MAX,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        c"pg_jsonschema.max_schema_bytes",
+        c"Maximum length of a schema's text.",
+        c"Checked when a jsonschema value is created. Zero disables the limit.",
+        &MAX_SCHEMA_BYTES,
+        0,
+        i32::MAX,
+        GucContext::Suset,
+        GucFlags::UNIT_BYTE,
+    );
+    GucRegistry::define_int_guc(
+        c"pg_jsonschema.max_schema_depth",
+        c"Maximum nesting depth of objects and arrays in a schema.",
+        c"Checked when a jsonschema value is created. Zero disables the limit.",
+        &MAX_SCHEMA_DEPTH,
+        0,
+        i32::MAX,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        c"pg_jsonschema.max_ref_depth",
+        c"Maximum length of a chain of $refs to registered schemas.",
+        c"Checked when a jsonschema value is created. Zero disables the limit.",
+        &MAX_REF_DEPTH,
+        0,
+        i32::MAX,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        c"pg_jsonschema.max_compile_ms",
+        c"Maximum time spent compiling a schema.",
+        c"Checked when a jsonschema value is created, only as registered schemas are retrieved and after compiling has finished: it rejects slow schemas but does not interrupt compilation. Zero disables the limit.",
+        &MAX_COMPILE_MS,
+        0,
+        i32::MAX,
+        GucContext::Suset,
+        GucFlags::UNIT_MS,
+    );
+}
@@ -0,0 +1,163 @@
+//! `jsonb` arguments decoded straight from PostgreSQL's binary format.
+//!
+//! pgrx's [`pgrx::JsonB`] renders a value to text with `jsonb_out` and parses
+//! that text back. [`JsonbInstance`] walks the `JsonbContainer` with the jsonb
+//! iterator instead, building the [`Value`] the validator works on without the
+//! intermediate text.
+use std::ffi::CStr;
+
+use pgrx::{
+    callconv::{Arg, ArgAbi},
+    pgrx_sql_entity_graph::metadata::{
+        ArgumentError, Returns, ReturnsError, SqlMapping, SqlTranslatable,
+    },
+    *,
+};
+use serde_json::{Map, Number, Value};
+
+/// A `jsonb` argument, decoded without going through its text form.
+pub(crate) struct JsonbInstance(pub Value);
+
+/// A container being decoded.
+enum Frame {
+    /// Elements so far, and whether this is the pseudo-array wrapping a
+    /// top-level scalar.
+    Array(Vec<Value>, bool),
+    /// Members so far, and the key of the member being decoded.
+    Object(Map<String, Value>, Option<String>),
+}
+
+/// Decodes the jsonb value held by `container`.
+///
+/// # Safety
+/// `container` must point to the root container of a detoasted `jsonb`.
+unsafe fn decode(container: *mut pg_sys::JsonbContainer) -> Value {
+    use pg_sys::JsonbIteratorToken::*;
+
+    let mut iterator = unsafe { pg_sys::JsonbIteratorInit(container) };
+    let mut value: pg_sys::JsonbValue = unsafe { std::mem::zeroed() };
+    let mut stack = Vec::new();
+    loop {
+        let token = unsafe { pg_sys::JsonbIteratorNext(&mut iterator, &mut value, false) };
+        let decoded = match token {
+            WJB_BEGIN_ARRAY => {
+                check_for_interrupts!();
+                let array = unsafe { value.val.array };
+                stack.push(Frame::Array(
+                    Vec::with_capacity(array.nElems as usize),
+                    array.rawScalar,
+                ));
+                continue;
+            }
+            WJB_BEGIN_OBJECT => {
+                check_for_interrupts!();
+                let object = unsafe { value.val.object };
+                stack.push(Frame::Object(
+                    Map::with_capacity(object.nPairs as usize),
+                    None,
+                ));
+                continue;
+            }
+            WJB_KEY => {
+                if let Some(Frame::Object(_, key)) = stack.last_mut() {
+                    *key = Some(unsafe { string(&value) });
+                }
+                continue;
+            }
+            WJB_ELEM | WJB_VALUE => unsafe { scalar(&value) },
+            WJB_END_ARRAY | WJB_END_OBJECT => match stack.pop() {
+                Some(Frame::Array(mut elems, true)) => elems.pop().unwrap_or(Value::Null),
+                Some(Frame::Array(elems, false)) => Value::Array(elems),
+                Some(Frame::Object(members, _)) => Value::Object(members),
+                None => pgrx::error!("corrupt jsonb value: unbalanced container"),
+            },
+            _ => pgrx::error!("corrupt jsonb value: unexpected iterator token {token}"),
+        };
+        match stack.last_mut() {
+            Some(Frame::Array(elems, _)) => elems.push(decoded),
+            Some(Frame::Object(members, key)) => {
+                members.insert(key.take().unwrap_or_default(), decoded);
+            }
+            None => return decoded,
+        }
+    }
+}
+
+/// Decodes a scalar; the iterator descends into containers itself.
+unsafe fn scalar(value: &pg_sys::JsonbValue) -> Value {
+    use pg_sys::jbvType::*;
+
+    match value.type_ {
+        jbvNull => Value::Null,
+        jbvBool => Value::Bool(unsafe { value.val.boolean }),
+        jbvString => Value::String(unsafe { string(value) }),
+        jbvNumeric => Value::Number(unsafe { number(value.val.numeric) }),
+        other => pgrx::error!("corrupt jsonb value: unexpected scalar type {other}"),
+    }
+}
+
+unsafe fn string(value: &pg_sys::JsonbValue) -> String {
+    let string = unsafe { value.val.string };
+    let bytes = unsafe { std::slice::from_raw_parts(string.val as *const u8, string.len as usize) };
+    String::from_utf8(bytes.to_vec())
+        .unwrap_or_else(|err| pgrx::error!("jsonb string is not valid UTF-8: {err}"))
+}
+
+/// Converts with `numeric_out`, as `jsonb_out` does, so numbers keep the
+/// precision and scale they were written with.
+unsafe fn number(numeric: pg_sys::Numeric) -> Number {
+    unsafe {
+        let text = direct_function_call::<&CStr>(pg_sys::numeric_out, &[Some(numeric.into())])
+            .expect("numeric_out returned NULL");
+        let number = text
+            .to_str()
+            .ok()
+            .and_then(|text| text.parse().ok())
+            .unwrap_or_else(|| pgrx::error!("jsonb number {text:?} is not valid JSON"));
+        pg_sys::pfree(text.as_ptr() as *mut _);
+        number
+    }
+}
+
+impl FromDatum for JsonbInstance {
+    unsafe fn from_polymorphic_datum(
+        datum: pg_sys::Datum,
+        is_null: bool,
+        _typoid: pg_sys::Oid,
+    ) -> Option<Self> {
+        if is_null {
+            return None;
+        }
+        unsafe {
+            let varlena = datum.cast_mut_ptr::<pg_sys::varlena>();
+            // Containers are read in place, which needs an aligned, 4-byte
+            // header copy rather than a packed one.
+            let jsonb = pg_sys::pg_detoast_datum(varlena) as *mut pg_sys::Jsonb;
+            let value = decode(&mut (*jsonb).root);
+            if jsonb.cast() != varlena {
+                pg_sys::pfree(jsonb.cast());
+            }
+            Some(Self(value))
+        }
+    }
+}
+
+unsafe impl<'fcx> ArgAbi<'fcx> for JsonbInstance {
+    unsafe fn unbox_arg_unchecked(arg: Arg<'_, 'fcx>) -> Self {
+        let index = arg.index();
+        unsafe {
+            arg.unbox_arg_using_from_datum()
+                .unwrap_or_else(|| panic!("argument {index} must not be null"))
+        }
+    }
+}
+
+unsafe impl SqlTranslatable for JsonbInstance {
+    fn argument_sql() -> Result<SqlMapping, ArgumentError> {
+        Ok(SqlMapping::literal("jsonb"))
+    }
+
+    fn return_sql() -> Result<Returns, ReturnsError> {
+        Ok(Returns::One(SqlMapping::literal("jsonb")))
+    }
+}
@@ -1,184 +1,1976 @@
 mod compiled;
+mod guc;
+mod jsonb;
+mod output;
+mod selectivity;
 
 use pgrx::*;
 
-use compiled::{JsonSchema, fn_extra_get_or_compile};
+use compiled::{
+    JsonSchema, fn_extra_get_or_compile, fn_extra_get_or_compile_arg,
+    fn_extra_get_or_compile_value, get_or_compile, schema_by_name,
+};
+use jsonb::JsonbInstance;
 
 pg_module_magic!();
 
+#[pg_guard]
+pub extern "C-unwind" fn _PG_init() {
+    guc::init();
+    compiled::init();
+}
+
+#[pg_extern(immutable, strict, parallel_safe)]
+fn json_matches_schema(schema: Json, instance: Json, fcinfo: pg_sys::FunctionCallInfo) -> bool {
+    let validator = unsafe { fn_extra_get_or_compile_value(&schema.0, fcinfo) }
+        .unwrap_or_else(|err| pgrx::error!("invalid JSON schema: {err}"));
+    validator.is_valid(&instance.0)
+}
+
+#[pg_extern(immutable, strict, parallel_safe)]
+fn jsonb_matches_schema(
+    schema: Json,
+    instance: JsonbInstance,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> bool {
+    let validator = unsafe { fn_extra_get_or_compile_value(&schema.0, fcinfo) }
+        .unwrap_or_else(|err| pgrx::error!("invalid JSON schema: {err}"));
+    validator.is_valid(&instance.0)
+}
+
+#[pg_extern(immutable, strict, parallel_safe)]
+fn jsonschema_is_valid(schema: Json) -> bool {
+    match jsonschema::meta::validate(&schema.0) {
+        Ok(_) => true,
+        Err(err) => {
+            notice!("Invalid JSON schema at path: {}", err.instance_path());
+            false
+        }
+    }
+}
+
+#[pg_extern(immutable, strict, parallel_safe)]
+fn jsonschema_validation_errors(
+    schema: Json,
+    instance: Json,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Vec<String> {
+    let validator = match unsafe { fn_extra_get_or_compile_value(&schema.0, fcinfo) } {
+        Ok(v) => v,
+        Err(err) => return vec![err.to_string()],
+    };
+    validator
+        .iter_errors(&instance.0)
+        .inspect(|_| check_for_interrupts!())
+        .map(|err| err.to_string())
+        .collect()
+}
+
+#[pg_extern(immutable, strict, parallel_safe)]
+fn jsonschema_validation_error_details(
+    schema: Json,
+    instance: Json,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Vec<JsonB> {
+    let validator = match unsafe { fn_extra_get_or_compile_value(&schema.0, fcinfo) } {
+        Ok(v) => v,
+        Err(err) => return vec![error_details(&err)],
+    };
+    validator
+        .iter_errors(&instance.0)
+        .inspect(|_| check_for_interrupts!())
+        .map(|err| error_details(&err))
+        .collect()
+}
+
+/// Structured form of a validation error: its message, JSON pointers into the
+/// instance and schema, the failing keyword and the offending value.
+fn error_details(err: &jsonschema::ValidationError) -> JsonB {
+    JsonB(serde_json::json!({
+        "message": err.to_string(),
+        "instance_path": err.instance_path().as_str(),
+        "schema_path": err.schema_path().as_str(),
+        "keyword": err.kind().keyword(),
+        "instance": err.instance(),
+    }))
+}
+
+#[pg_extern(immutable, strict, parallel_safe)]
+fn jsonschema_from_json(schema: pgrx::Json) -> JsonSchema {
+    JsonSchema::compile(schema.0)
+}
+
+#[pg_extern(immutable, strict, parallel_safe)]
+fn jsonschema_from_jsonb(schema: pgrx::JsonB) -> JsonSchema {
+    JsonSchema::compile(schema.0)
+}
+
+#[pg_extern(immutable, strict, parallel_safe, name = "jsonschema_from_json")]
+fn jsonschema_from_json_with_draft(schema: pgrx::Json, draft: &str) -> JsonSchema {
+    JsonSchema::compile_with_draft(schema.0, Some(compiled::draft::parse(draft)))
+}
+
+#[pg_extern(immutable, strict, parallel_safe, name = "jsonschema_from_jsonb")]
+fn jsonschema_from_jsonb_with_draft(schema: pgrx::JsonB, draft: &str) -> JsonSchema {
+    JsonSchema::compile_with_draft(schema.0, Some(compiled::draft::parse(draft)))
+}
+
+pgrx::extension_sql!(
+    r#"
+    CREATE CAST (json AS jsonschema)
+        WITH FUNCTION jsonschema_from_json(json);
+
+    CREATE CAST (jsonb AS jsonschema)
+        WITH FUNCTION jsonschema_from_jsonb(jsonb);
+    "#,
+    name = "jsonschema_casts",
+    requires = [jsonschema_from_json, jsonschema_from_jsonb],
+);
+
+pgrx::extension_sql!(
+    r#"
+    CREATE SCHEMA jsonschema;
+
+    CREATE TABLE jsonschema.registry (
+        uri text PRIMARY KEY,
+        schema jsonb NOT NULL
+    );
+
+    GRANT USAGE ON SCHEMA jsonschema TO PUBLIC;
+    GRANT SELECT ON jsonschema.registry TO PUBLIC;
+
+    SELECT pg_catalog.pg_extension_config_dump('jsonschema.registry', '');
+
+    CREATE TRIGGER registry_invalidate
+        AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON jsonschema.registry
+        FOR EACH STATEMENT EXECUTE FUNCTION jsonschema_registry_invalidate();
+    "#,
+    name = "jsonschema_registry",
+    requires = [jsonschema_registry_invalidate],
+);
+
+pgrx::extension_sql!(
+    r#"
+    CREATE TABLE jsonschema.formats (
+        name text PRIMARY KEY,
+        checker regprocedure NOT NULL
+    );
+
+    GRANT SELECT ON jsonschema.formats TO PUBLIC;
+
+    SELECT pg_catalog.pg_extension_config_dump('jsonschema.formats', '');
+
+    CREATE TRIGGER formats_invalidate
+        AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON jsonschema.formats
+        FOR EACH STATEMENT EXECUTE FUNCTION jsonschema_registry_invalidate();
+
+    CREATE FUNCTION jsonschema_register_format(name text, checker regprocedure)
+    RETURNS void
+    LANGUAGE plpgsql
+    AS $$
+    BEGIN
+        IF NOT EXISTS (
+            SELECT FROM pg_catalog.pg_proc p
+            WHERE p.oid = $2
+              AND p.pronargs = 1
+              AND p.proargtypes[0] = 'text'::regtype
+              AND p.prorettype = 'boolean'::regtype
+              AND NOT p.proretset
+        ) THEN
+            RAISE EXCEPTION 'format checker % must take text and return boolean', $2;
+        END IF;
+
+        INSERT INTO jsonschema.formats (name, checker)
+        VALUES ($1, $2)
+        ON CONFLICT ON CONSTRAINT formats_pkey DO UPDATE SET checker = EXCLUDED.checker;
+    END;
+    $$;
+    "#,
+    name = "jsonschema_formats",
+    requires = ["jsonschema_registry"],
+);
+
+pgrx::extension_sql!(
+    r#"
+    CREATE TABLE jsonschema.keywords (
+        name text PRIMARY KEY,
+        validator regprocedure NOT NULL
+    );
+
+    GRANT SELECT ON jsonschema.keywords TO PUBLIC;
+
+    SELECT pg_catalog.pg_extension_config_dump('jsonschema.keywords', '');
+
+    CREATE TRIGGER keywords_invalidate
+        AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON jsonschema.keywords
+        FOR EACH STATEMENT EXECUTE FUNCTION jsonschema_registry_invalidate();
+
+    CREATE FUNCTION jsonschema_register_keyword(name text, validator regprocedure)
+    RETURNS void
+    LANGUAGE plpgsql
+    AS $$
+    BEGIN
+        IF NOT EXISTS (
+            SELECT FROM pg_catalog.pg_proc p
+            WHERE p.oid = $2
+              AND p.pronargs = 2
+              AND p.proargtypes[0] = 'jsonb'::regtype
+              AND p.proargtypes[1] = 'jsonb'::regtype
+              AND p.prorettype = 'text'::regtype
+              AND NOT p.proretset
+        ) THEN
+            RAISE EXCEPTION 'keyword validator % must take (jsonb, jsonb) and return text', $2;
+        END IF;
+
+        -- Keywords of any supported draft, and this extension's own, keep
+        -- their meaning.
+        IF $1 LIKE 'x-pg-%' OR $1 = ANY (ARRAY[
+            '$schema', '$id', 'id', '$ref', '$anchor', '$dynamicRef', '$dynamicAnchor',
+            '$recursiveRef', '$recursiveAnchor', '$vocabulary', '$comment', '$defs',
+            'definitions', 'allOf', 'anyOf', 'oneOf', 'not', 'if', 'then', 'else',
+            'dependentSchemas', 'prefixItems', 'items', 'additionalItems', 'contains',
+            'properties', 'patternProperties', 'additionalProperties', 'propertyNames',
+            'unevaluatedItems', 'unevaluatedProperties', 'type', 'enum', 'const',
+            'multipleOf', 'maximum', 'exclusiveMaximum', 'minimum', 'exclusiveMinimum',
+            'maxLength', 'minLength', 'pattern', 'maxItems', 'minItems', 'uniqueItems',
+            'maxContains', 'minContains', 'maxProperties', 'minProperties', 'required',
+            'dependentRequired', 'dependencies', 'format', 'contentEncoding',
+            'contentMediaType', 'contentSchema', 'title', 'description', 'default',
+            'deprecated', 'readOnly', 'writeOnly', 'examples'
+        ]) THEN
+            RAISE EXCEPTION 'keyword name "%" is reserved', $1;
+        END IF;
+
+        INSERT INTO jsonschema.keywords (name, validator)
+        VALUES ($1, $2)
+        ON CONFLICT ON CONSTRAINT keywords_pkey DO UPDATE SET validator = EXCLUDED.validator;
+    END;
+    $$;
+    "#,
+    name = "jsonschema_keywords",
+    requires = ["jsonschema_registry"],
+);
+
+#[pg_extern(stable, strict, parallel_safe)]
+fn jsonschema_by_name(name: &str) -> JsonSchema {
+    JsonSchema::clone(&schema_by_name(name))
+}
+
+#[pg_extern(stable, strict, parallel_safe)]
+fn jsonb_matches_named_schema(
+    name: &str,
+    instance: JsonbInstance,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> bool {
+    let schema = schema_by_name(name);
+    let validator = unsafe { fn_extra_get_or_compile(&schema, fcinfo) };
+    validator.is_valid(&instance.0)
+}
+
+#[pg_extern(immutable, strict, parallel_safe)]
+fn json_matches_compiled_schema(
+    schema: JsonSchema,
+    instance: Json,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> bool {
+    let validator = unsafe { fn_extra_get_or_compile(&schema, fcinfo) };
+    validator.is_valid(&instance.0)
+}
+
+#[pg_extern(immutable, strict, parallel_safe)]
+fn jsonb_matches_compiled_schema(
+    schema: JsonSchema,
+    instance: JsonbInstance,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> bool {
+    let validator = unsafe { fn_extra_get_or_compile(&schema, fcinfo) };
+    validator.is_valid(&instance.0)
+}
+
+#[pg_extern(immutable, strict, parallel_safe)]
+fn json_not_matches_compiled_schema(
+    schema: JsonSchema,
+    instance: Json,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> bool {
+    let validator = unsafe { fn_extra_get_or_compile(&schema, fcinfo) };
+    !validator.is_valid(&instance.0)
+}
+
+#[pg_extern(immutable, strict, parallel_safe)]
+fn jsonb_not_matches_compiled_schema(
+    schema: JsonSchema,
+    instance: JsonbInstance,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> bool {
+    let validator = unsafe { fn_extra_get_or_compile(&schema, fcinfo) };
+    !validator.is_valid(&instance.0)
+}
+
+#[pg_extern(immutable, strict, parallel_safe)]
+fn json_matches_jsonschema(
+    instance: Json,
+    schema: JsonSchema,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> bool {
+    let validator = unsafe { fn_extra_get_or_compile_arg(&schema, 1, fcinfo) };
+    validator.is_valid(&instance.0)
+}
+
+#[pg_extern(immutable, strict, parallel_safe)]
+fn jsonb_matches_jsonschema(
+    instance: JsonbInstance,
+    schema: JsonSchema,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> bool {
+    let validator = unsafe { fn_extra_get_or_compile_arg(&schema, 1, fcinfo) };
+    validator.is_valid(&instance.0)
+}
+
+#[pg_extern(immutable, strict, parallel_safe)]
+fn json_not_matches_jsonschema(
+    instance: Json,
+    schema: JsonSchema,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> bool {
+    let validator = unsafe { fn_extra_get_or_compile_arg(&schema, 1, fcinfo) };
+    !validator.is_valid(&instance.0)
+}
+
+#[pg_extern(immutable, strict, parallel_safe)]
+fn jsonb_not_matches_jsonschema(
+    instance: JsonbInstance,
+    schema: JsonSchema,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> bool {
+    let validator = unsafe { fn_extra_get_or_compile_arg(&schema, 1, fcinfo) };
+    !validator.is_valid(&instance.0)
+}
+
+pgrx::extension_sql!(
+    r#"
+    CREATE OPERATOR @~ (
+        LEFTARG = json, RIGHTARG = jsonschema, FUNCTION = json_matches_jsonschema,
+        COMMUTATOR = ~@, NEGATOR = !@~,
+        RESTRICT = matchingsel, JOIN = matchingjoinsel
+    );
+    CREATE OPERATOR ~@ (
+        LEFTARG = jsonschema, RIGHTARG = json, FUNCTION = json_matches_compiled_schema,
+        COMMUTATOR = @~, NEGATOR = !~@,
+        RESTRICT = matchingsel, JOIN = matchingjoinsel
+    );
+    CREATE OPERATOR !@~ (
+        LEFTARG = json, RIGHTARG = jsonschema, FUNCTION = json_not_matches_jsonschema,
+        COMMUTATOR = !~@, NEGATOR = @~,
+        RESTRICT = matchingsel, JOIN = matchingjoinsel
+    );
+    CREATE OPERATOR !~@ (
+        LEFTARG = jsonschema, RIGHTARG = json, FUNCTION = json_not_matches_compiled_schema,
+        COMMUTATOR = !@~, NEGATOR = ~@,
+        RESTRICT = matchingsel, JOIN = matchingjoinsel
+    );
+
+    CREATE OPERATOR @~ (
+        LEFTARG = jsonb, RIGHTARG = jsonschema, FUNCTION = jsonb_matches_jsonschema,
+        COMMUTATOR = ~@, NEGATOR = !@~,
+        RESTRICT = matchingsel, JOIN = matchingjoinsel
+    );
+    CREATE OPERATOR ~@ (
+        LEFTARG = jsonschema, RIGHTARG = jsonb, FUNCTION = jsonb_matches_compiled_schema,
+        COMMUTATOR = @~, NEGATOR = !~@,
+        RESTRICT = matchingsel, JOIN = matchingjoinsel
+    );
+    CREATE OPERATOR !@~ (
+        LEFTARG = jsonb, RIGHTARG = jsonschema, FUNCTION = jsonb_not_matches_jsonschema,
+        COMMUTATOR = !~@, NEGATOR = @~,
+        RESTRICT = matchingsel, JOIN = matchingjoinsel
+    );
+    CREATE OPERATOR !~@ (
+        LEFTARG = jsonschema, RIGHTARG = jsonb, FUNCTION = jsonb_not_matches_compiled_schema,
+        COMMUTATOR = !@~, NEGATOR = ~@,
+        RESTRICT = matchingsel, JOIN = matchingjoinsel
+    );
+    "#,
+    name = "jsonschema_operators",
+    requires = [
+        json_matches_compiled_schema,
+        jsonb_matches_compiled_schema,
+        json_not_matches_compiled_schema,
+        jsonb_not_matches_compiled_schema,
+        json_matches_jsonschema,
+        jsonb_matches_jsonschema,
+        json_not_matches_jsonschema,
+        jsonb_not_matches_jsonschema,
+    ],
+);
+
+#[pg_extern(immutable, strict, parallel_safe)]
+fn json_validation_errors_compiled(
+    schema: JsonSchema,
+    instance: Json,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Vec<String> {
+    let validator = unsafe { fn_extra_get_or_compile(&schema, fcinfo) };
+    validator
+        .iter_errors(&instance.0)
+        .inspect(|_| check_for_interrupts!())
+        .map(|err| err.to_string())
+        .collect()
+}
+
+#[pg_extern(immutable, strict, parallel_safe)]
+fn jsonb_validation_errors_compiled(
+    schema: JsonSchema,
+    instance: JsonbInstance,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Vec<String> {
+    let validator = unsafe { fn_extra_get_or_compile(&schema, fcinfo) };
+    validator
+        .iter_errors(&instance.0)
+        .inspect(|_| check_for_interrupts!())
+        .map(|err| err.to_string())
+        .collect()
+}
+
+#[pg_extern(immutable, strict, parallel_safe)]
+fn json_validation_error_details_compiled(
+    schema: JsonSchema,
+    instance: Json,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Vec<JsonB> {
+    let validator = unsafe { fn_extra_get_or_compile(&schema, fcinfo) };
+    validator
+        .iter_errors(&instance.0)
+        .inspect(|_| check_for_interrupts!())
+        .map(|err| error_details(&err))
+        .collect()
+}
+
+#[pg_extern(immutable, strict, parallel_safe)]
+fn jsonb_validation_error_details_compiled(
+    schema: JsonSchema,
+    instance: JsonbInstance,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Vec<JsonB> {
+    let validator = unsafe { fn_extra_get_or_compile(&schema, fcinfo) };
+    validator
+        .iter_errors(&instance.0)
+        .inspect(|_| check_for_interrupts!())
+        .map(|err| error_details(&err))
+        .collect()
+}
+
+/// Further errors listed in the HINT of a failed assertion; the rest are only
+/// counted.
+const ASSERTION_HINT_ERRORS: usize = 5;
+
+/// Returns `instance` unchanged if it matches `schema`, otherwise raises a
+/// `check_violation` whose DETAIL describes the first error, CONTEXT gives its
+/// instance location and HINT lists further errors.
+#[pg_extern(immutable, strict, parallel_safe)]
+fn jsonb_assert_matches_schema(
+    schema: JsonSchema,
+    instance: JsonbInstance,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> JsonB {
+    let validator = unsafe { fn_extra_get_or_compile(&schema, fcinfo) };
+    let failure = {
+        let mut errors = validator
+            .iter_errors(&instance.0)
+            .inspect(|_| check_for_interrupts!());
+        errors.next().map(|first| {
+            let mut further = Vec::new();
+            let mut count = 0;
+            for err in errors {
+                if further.len() < ASSERTION_HINT_ERRORS {
+                    further.push(format!("{err} at \"{}\"", err.instance_path()));
+                }
+                count += 1;
+            }
+            let hint = (count > 0).then(|| {
+                let mut hint = format!("{count} further error(s): {}", further.join("; "));
+                if count > further.len() {
+                    hint.push_str(&format!("; and {} more", count - further.len()));
+                }
+                hint
+            });
+            (
+                first.to_string(),
+                first.instance_path().as_str().to_owned(),
+                hint,
+            )
+        })
+    };
+    let Some((detail, location, hint)) = failure else {
+        return JsonB(instance.0);
+    };
+
+    let mut report = pg_sys::panic::ErrorReport::new(
+        PgSqlErrorCode::ERRCODE_CHECK_VIOLATION,
+        "jsonb instance does not match schema",
+        function_name!(),
+    )
+    .set_detail(detail);
+    if let Some(hint) = hint {
+        report = report.set_hint(hint);
+    }
+    push_instance_location_context(&location);
+    report.report(PgLogLevel::ERROR);
+    unreachable!()
+}
+
+unsafe extern "C-unwind" {
+    // Behind the `errcontext()` macro; not part of the generated bindings.
+    fn errcontext_msg(fmt: *const std::ffi::c_char, ...) -> std::ffi::c_int;
+}
+
+/// Reports `location` as the CONTEXT of the error about to be raised.
+///
+/// The callback is left on `error_context_stack`: it is called when the error
+/// is raised, and the stack is restored wherever the error is caught.
+fn push_instance_location_context(location: &str) {
+    unsafe extern "C-unwind" fn callback(arg: *mut std::ffi::c_void) {
+        unsafe {
+            errcontext_msg(
+                c"instance location \"%s\"".as_ptr(),
+                arg.cast_const().cast(),
+            )
+        };
+    }
+
+    unsafe {
+        let context = pg_sys::palloc0(std::mem::size_of::<pg_sys::ErrorContextCallback>())
+            as *mut pg_sys::ErrorContextCallback;
+        (*context).previous = pg_sys::error_context_stack;
+        (*context).callback = Some(callback);
+        (*context).arg = pg_sys::AsPgCStr::as_pg_cstr(&location).cast();
+        pg_sys::error_context_stack = context;
+    }
+}
+
 #[pg_extern(immutable, strict, parallel_safe)]
-fn json_matches_schema(schema: Json, instance: Json) -> bool {
-    jsonschema::is_valid(&schema.0, &instance.0)
+fn jsonschema_evaluate(
+    schema: JsonSchema,
+    instance: JsonbInstance,
+    format: default!(&str, "'basic'"),
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> JsonB {
+    let validator = unsafe { fn_extra_get_or_compile(&schema, fcinfo) };
+    JsonB(output::evaluate(&validator, &instance.0, format))
 }
 
 #[pg_extern(immutable, strict, parallel_safe)]
-fn jsonb_matches_schema(schema: Json, instance: JsonB) -> bool {
-    jsonschema::is_valid(&schema.0, &instance.0)
+fn jsonschema_validation_report(
+    schema: JsonSchema,
+    instance: JsonbInstance,
+) -> TableIterator<
+    'static,
+    (
+        name!(instance_path, String),
+        name!(schema_path, String),
+        name!(keyword, String),
+        name!(message, String),
+    ),
+> {
+    // `fn_extra` holds the SRF's `FuncCallContext`, so only the LRU is used here.
+    let validator = get_or_compile(&schema);
+    let rows: Vec<_> = validator
+        .iter_errors(&instance.0)
+        .inspect(|_| check_for_interrupts!())
+        .map(|err| {
+            (
+                err.instance_path().as_str().to_owned(),
+                err.schema_path().as_str().to_owned(),
+                err.kind().keyword().to_owned(),
+                err.to_string(),
+            )
+        })
+        .collect();
+    TableIterator::new(rows)
+}
+
+#[pg_extern(parallel_restricted)]
+fn jsonschema_cache_stats() -> TableIterator<
+    'static,
+    (
+        name!(entries, i32),
+        name!(capacity, i32),
+        name!(hits, i64),
+        name!(misses, i64),
+        name!(evictions, i64),
+        name!(compile_time_ms, f64),
+    ),
+> {
+    let stats = compiled::cache::stats();
+    TableIterator::once((
+        stats.entries as i32,
+        stats.capacity as i32,
+        stats.hits as i64,
+        stats.misses as i64,
+        stats.evictions as i64,
+        stats.compile_time.as_secs_f64() * 1000.0,
+    ))
 }
 
-#[pg_extern(immutable, strict, parallel_safe)]
-fn jsonschema_is_valid(schema: Json) -> bool {
-    match jsonschema::meta::validate(&schema.0) {
-        Ok(_) => true,
-        Err(err) => {
-            notice!("Invalid JSON schema at path: {}", err.instance_path());
-            false
-        }
+#[pg_extern(parallel_restricted)]
+fn jsonschema_cache_entries() -> TableIterator<
+    'static,
+    (
+        name!(fingerprint, Vec<u8>),
+        name!(hits, i64),
+        name!(bytes, i64),
+    ),
+> {
+    TableIterator::new(compiled::cache::entries().into_iter().map(|entry| {
+        (
+            entry.fingerprint.to_vec(),
+            entry.hits as i64,
+            entry.bytes as i64,
+        )
+    }))
+}
+
+#[pg_extern(parallel_restricted)]
+fn jsonschema_cache_reset() {
+    compiled::cache::reset();
+}
+
+#[pg_schema]
+#[cfg(any(test, feature = "pg_test"))]
+mod tests {
+    use pgrx::*;
+    use serde_json::json;
+
+    macro_rules! compiled_schema_tests {
+        (json { $($name:ident: $schema:literal, $instance:literal => $expected:literal)* }) => {$(
+            #[pg_test]
+            fn $name() {
+                let result = Spi::get_one::<bool>(concat!(
+                    "SELECT json_matches_compiled_schema('", $schema, "'::jsonschema, '", $instance, "'::json)"
+                )).unwrap().unwrap();
+                assert_eq!(result, $expected);
+            }
+        )*};
+        (jsonb { $($name:ident: $schema:literal, $instance:literal => $expected:literal)* }) => {$(
+            #[pg_test]
+            fn $name() {
+                let result = Spi::get_one::<bool>(concat!(
+                    "SELECT jsonb_matches_compiled_schema('", $schema, "'::jsonschema, '", $instance, "'::jsonb)"
+                )).unwrap().unwrap();
+                assert_eq!(result, $expected);
+            }
+        )*};
+        (errors_json { $($name:ident: $schema:literal, $instance:literal => [$($err:literal),*])* }) => {$(
+            #[pg_test]
+            fn $name() {
+                let errors = Spi::get_one::<Vec<String>>(concat!(
+                    "SELECT json_validation_errors_compiled('", $schema, "'::jsonschema, '",
+                    $instance, "'::json)"
+                )).unwrap().unwrap();
+                assert_eq!(errors, [$($err),*]);
+            }
+        )*};
+        (errors_jsonb { $($name:ident: $schema:literal, $instance:literal => [$($err:literal),*])* }) => {$(
+            #[pg_test]
+            fn $name() {
+                let errors = Spi::get_one::<Vec<String>>(concat!(
+                    "SELECT jsonb_validation_errors_compiled('", $schema, "'::jsonschema, '",
+                    $instance, "'::jsonb)"
+                )).unwrap().unwrap();
+                assert_eq!(errors, [$($err),*]);
+            }
+        )*};
+    }
+
+    compiled_schema_tests!(json {
+        test_json_matches_compiled_schema: r#"{"type":"string"}"#, r#""hello""# => true
+        test_json_rejects_compiled_schema: r#"{"type":"string"}"#, r#"42"#      => false
+    });
+
+    compiled_schema_tests!(jsonb {
+        test_jsonb_matches_compiled_schema: r#"{"type":"string"}"#, r#""hello""# => true
+        test_jsonb_rejects_compiled_schema: r#"{"type":"string"}"#, r#"42"#      => false
+    });
+
+    compiled_schema_tests!(errors_json {
+        test_validation_errors_compiled_with_error:
+            r#"{"maxLength":4}"#, r#""toolong""#
+            => [r#""toolong" is longer than 4 characters"#]
+    });
+
+    compiled_schema_tests!(errors_jsonb {
+        test_validation_errors_compiled_jsonb:
+            r#"{"type":"string"}"#, r#"42"#
+            => ["42 is not of type \"string\""]
+        test_validation_errors_compiled_jsonb_object:
+            r#"{"type":"object","required":["name"],"properties":{"name":{"type":"string"}}}"#,
+            r#"{"name":42}"#
+            => ["42 is not of type \"string\""]
+    });
+
+    #[pg_test]
+    fn test_validation_error_details_compiled_jsonb() {
+        let errors = Spi::get_one::<Vec<JsonB>>(
+            r#"
+            SELECT jsonb_validation_error_details_compiled(
+                '{"type":"object","properties":{"name":{"type":"string"}}}'::jsonschema,
+                '{"name":42}'::jsonb
+            )
+            "#,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].0,
+            json!({
+                "message": "42 is not of type \"string\"",
+                "instance_path": "/name",
+                "schema_path": "/properties/name/type",
+                "keyword": "type",
+                "instance": 42,
+            })
+        );
+    }
+
+    #[pg_test]
+    fn test_validation_error_details_compiled_json_no_errors() {
+        let errors = Spi::get_one::<Vec<JsonB>>(
+            r#"SELECT json_validation_error_details_compiled('{"maxLength":4}'::jsonschema, '"foo"'::json)"#,
+        )
+        .unwrap()
+        .unwrap();
+        assert!(errors.is_empty());
+    }
+
+    #[pg_test]
+    fn test_validation_report_rows() {
+        let keywords = Spi::get_one::<Vec<String>>(
+            r#"
+            SELECT array_agg(keyword || ':' || instance_path ORDER BY instance_path)
+            FROM jsonschema_validation_report(
+                '{"properties":{"a":{"type":"string"},"b":{"minimum":0}}}'::jsonschema,
+                '{"a":1,"b":-1}'::jsonb
+            )
+            "#,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(keywords, ["type:/a", "minimum:/b"]);
+    }
+
+    #[pg_test]
+    fn test_validation_report_lateral_group_by() {
+        Spi::run(
+            r#"
+            CREATE TEMP TABLE report_docs (doc jsonb);
+            INSERT INTO report_docs VALUES ('"ok"'), ('1'), ('2'), ('"a-very-long-string"');
+        "#,
+        )
+        .unwrap();
+        let counts = Spi::get_one::<Vec<String>>(
+            r#"
+            SELECT array_agg(keyword || '=' || n ORDER BY keyword)
+            FROM (
+                SELECT r.keyword, count(*) AS n
+                FROM report_docs d
+                CROSS JOIN LATERAL jsonschema_validation_report(
+                    '{"type":"string","maxLength":4}'::jsonschema, d.doc
+                ) r
+                GROUP BY r.keyword
+            ) s
+            "#,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(counts, ["maxLength=1", "type=2"]);
+    }
+
+    #[pg_test]
+    fn test_evaluate_flag() {
+        let output = Spi::get_one::<JsonB>(
+            r#"SELECT jsonschema_evaluate('{"type":"string"}'::jsonschema, '42'::jsonb, 'flag')"#,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(output.0, json!({ "valid": false }));
+    }
+
+    #[pg_test]
+    fn test_evaluate_basic_is_default() {
+        let output = Spi::get_one::<JsonB>(
+            r#"SELECT jsonschema_evaluate('{"properties":{"a":{"type":"string"}}}'::jsonschema, '{"a":1}'::jsonb)"#,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(output.0["valid"], false);
+        let details = output.0["details"].as_array().unwrap();
+        assert!(details.iter().all(|unit| unit.get("details").is_none()));
+        assert!(details.iter().any(|unit| {
+            unit["instanceLocation"] == "/a"
+                && unit["errors"]["type"] == r#"1 is not of type "string""#
+        }));
+    }
+
+    #[pg_test]
+    fn test_evaluate_detailed_prunes_empty_units() {
+        let output = Spi::get_one::<JsonB>(
+            r#"
+            SELECT jsonschema_evaluate(
+                '{"type":"object","properties":{"a":{"type":"string"},"b":{"type":"integer"}}}'::jsonschema,
+                '{"a":"x","b":"y"}'::jsonb,
+                'detailed'
+            )
+            "#,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(output.0["valid"], false);
+        let verbose = Spi::get_one::<JsonB>(
+            r#"
+            SELECT jsonschema_evaluate(
+                '{"type":"object","properties":{"a":{"type":"string"},"b":{"type":"integer"}}}'::jsonschema,
+                '{"a":"x","b":"y"}'::jsonb,
+                'verbose'
+            )
+            "#,
+        )
+        .unwrap()
+        .unwrap();
+        let detailed = output.0.to_string();
+        assert!(!detailed.contains(r#""evaluationPath":"/type""#));
+        assert!(
+            verbose
+                .0
+                .to_string()
+                .contains(r#""evaluationPath":"/type""#)
+        );
+        assert!(detailed.contains(r#"\"y\" is not of type \"integer\""#));
+    }
+
+    #[pg_test]
+    #[should_panic(expected = "unknown output format \"terse\"")]
+    fn test_evaluate_unknown_format() {
+        Spi::run(r#"SELECT jsonschema_evaluate('{}'::jsonschema, '1'::jsonb, 'terse')"#).unwrap();
+    }
+
+    #[pg_test]
+    fn test_assert_matches_schema_returns_instance() {
+        let result = Spi::get_one::<JsonB>(
+            r#"SELECT jsonb_assert_matches_schema('{"type":"object"}'::jsonschema, '{"a":1}'::jsonb)"#,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(result.0, json!({ "a": 1 }));
+    }
+
+    #[pg_test]
+    fn test_assert_matches_schema_error_report() {
+        let report = PgTryBuilder::new(|| {
+            Spi::run(
+                r#"
+                SELECT jsonb_assert_matches_schema(
+                    '{"properties":{"a":{"type":"string"},"b":{"type":"string"}}}'::jsonschema,
+                    '{"a":1,"b":2}'::jsonb
+                )
+                "#,
+            )
+            .unwrap();
+            None
+        })
+        .catch_others(|err| match err {
+            pg_sys::panic::CaughtError::PostgresError(report) => Some((
+                report.sql_error_code(),
+                report.message().to_owned(),
+                report.detail().map(str::to_owned),
+                report.hint().map(str::to_owned),
+            )),
+            _ => None,
+        })
+        .execute();
+
+        let (code, message, detail, hint) = report.expect("assertion must raise");
+        assert_eq!(code, PgSqlErrorCode::ERRCODE_CHECK_VIOLATION);
+        assert_eq!(message, "jsonb instance does not match schema");
+        assert_eq!(detail.as_deref(), Some(r#"1 is not of type "string""#));
+        assert_eq!(
+            hint.as_deref(),
+            Some(r#"1 further error(s): 2 is not of type "string" at "/b""#)
+        );
+    }
+
+    #[pg_test]
+    fn test_assert_matches_schema_error_context() {
+        Spi::run(
+            r#"
+            CREATE FUNCTION pg_temp.assertion_context() RETURNS text LANGUAGE plpgsql AS $$
+            DECLARE
+                context text;
+            BEGIN
+                PERFORM jsonb_assert_matches_schema(
+                    '{"properties":{"a":{"items":{"type":"string"}}}}'::jsonschema,
+                    '{"a":["x",1]}'::jsonb
+                );
+                RETURN NULL;
+            EXCEPTION WHEN check_violation THEN
+                GET STACKED DIAGNOSTICS context = PG_EXCEPTION_CONTEXT;
+                RETURN context;
+            END $$
+            "#,
+        )
+        .unwrap();
+        let context = Spi::get_one::<String>("SELECT pg_temp.assertion_context()")
+            .unwrap()
+            .unwrap();
+        assert!(
+            context.starts_with(r#"instance location "/a/1""#),
+            "unexpected context: {context}"
+        );
+    }
+
+    #[pg_test]
+    fn test_assert_matches_schema_hint_is_capped() {
+        let hint = PgTryBuilder::new(|| {
+            Spi::run(
+                r#"
+                SELECT jsonb_assert_matches_schema(
+                    '{"items":{"type":"string"}}'::jsonschema,
+                    '[1,2,3,4,5,6,7,8]'::jsonb
+                )
+                "#,
+            )
+            .unwrap();
+            None
+        })
+        .catch_others(|err| match err {
+            pg_sys::panic::CaughtError::PostgresError(report) => report.hint().map(str::to_owned),
+            _ => None,
+        })
+        .execute();
+
+        assert_eq!(
+            hint.as_deref(),
+            Some(concat!(
+                r#"7 further error(s): 2 is not of type "string" at "/1"; "#,
+                r#"3 is not of type "string" at "/2"; 4 is not of type "string" at "/3"; "#,
+                r#"5 is not of type "string" at "/4"; 6 is not of type "string" at "/5"; "#,
+                "and 2 more",
+            ))
+        );
+    }
+
+    #[pg_test]
+    fn test_registry_ref_resolution() {
+        Spi::run(
+            r#"
+            INSERT INTO jsonschema.registry VALUES (
+                'https://example.com/address.json',
+                '{"type":"object","properties":{"zip":{"type":"string"}},"required":["zip"]}'
+            )
+        "#,
+        )
+        .unwrap();
+        let ok = Spi::get_one::<bool>(
+            r#"
+            SELECT jsonb_matches_compiled_schema(
+                '{"properties":{"address":{"$ref":"https://example.com/address.json"}}}'::jsonschema,
+                '{"address":{"zip":"12345"}}'::jsonb
+            )
+            "#,
+        )
+        .unwrap()
+        .unwrap();
+        assert!(ok);
+        let not_ok = Spi::get_one::<bool>(
+            r#"
+            SELECT jsonb_matches_compiled_schema(
+                '{"properties":{"address":{"$ref":"https://example.com/address.json"}}}'::jsonschema,
+                '{"address":{}}'::jsonb
+            )
+            "#,
+        )
+        .unwrap()
+        .unwrap();
+        assert!(!not_ok);
+    }
+
+    #[pg_test]
+    #[should_panic(expected = "is not registered in jsonschema.registry")]
+    fn test_registry_unknown_ref() {
+        Spi::run(r#"SELECT '{"$ref":"https://example.com/missing.json"}'::jsonschema"#).unwrap();
+    }
+
+    #[pg_test]
+    #[should_panic(expected = "violates check constraint")]
+    fn test_named_schema_check_constraint() {
+        Spi::run(
+            r#"
+            INSERT INTO jsonschema.registry VALUES (
+                'urn:example:customer',
+                '{"type":"object","required":["name"]}'
+            );
+            CREATE TEMP TABLE named_check (
+                data jsonb CHECK (jsonb_matches_named_schema('urn:example:customer', data))
+            );
+            INSERT INTO named_check VALUES ('{"name":"alice"}');
+        "#,
+        )
+        .unwrap();
+        Spi::run(r#"INSERT INTO named_check VALUES ('{}')"#).unwrap();
+    }
+
+    #[pg_test]
+    fn test_jsonschema_by_name() {
+        Spi::run(
+            r#"INSERT INTO jsonschema.registry VALUES ('urn:example:name', '{"type":"string","maxLength":3}')"#,
+        )
+        .unwrap();
+        let canonical =
+            Spi::get_one::<String>(r#"SELECT jsonschema_by_name('urn:example:name')::text"#)
+                .unwrap()
+                .unwrap();
+        assert_eq!(
+            canonical,
+            r#"{"$schema":"https://json-schema.org/draft/2020-12/schema","maxLength":3,"type":"string","x-pg-validate-formats":null}"#
+        );
+        let ok = Spi::get_one::<bool>(
+            r#"SELECT jsonb_matches_compiled_schema(jsonschema_by_name('urn:example:name'), '"abcd"')"#,
+        )
+        .unwrap()
+        .unwrap();
+        assert!(!ok);
+    }
+
+    #[pg_test]
+    #[should_panic(
+        expected = "schema \"urn:example:nope\" is not registered in jsonschema.registry"
+    )]
+    fn test_named_schema_unknown() {
+        Spi::run(r#"SELECT jsonb_matches_named_schema('urn:example:nope', '{}')"#).unwrap();
+    }
+
+    #[pg_test]
+    #[should_panic(
+        expected = "schema \"urn:example:nope\" is not registered in jsonschema.registry"
+    )]
+    fn test_jsonschema_by_name_unknown() {
+        Spi::run(r#"SELECT jsonschema_by_name('urn:example:nope')"#).unwrap();
+    }
+
+    #[pg_test]
+    fn test_registry_update_invalidates_named_schema() {
+        Spi::run(
+            r#"INSERT INTO jsonschema.registry VALUES ('urn:example:versioned', '{"type":"string"}')"#,
+        )
+        .unwrap();
+        let query = r#"SELECT jsonb_matches_named_schema('urn:example:versioned', '"x"')"#;
+        assert!(Spi::get_one::<bool>(query).unwrap().unwrap());
+
+        Spi::run(
+            r#"UPDATE jsonschema.registry SET schema = '{"type":"integer"}' WHERE uri = 'urn:example:versioned'"#,
+        )
+        .unwrap();
+        assert!(!Spi::get_one::<bool>(query).unwrap().unwrap());
+    }
+
+    #[pg_test]
+    fn test_named_schema_follows_settings() {
+        Spi::run(
+            r#"INSERT INTO jsonschema.registry VALUES ('urn:example:email', '{"format":"email"}')"#,
+        )
+        .unwrap();
+        let query = r#"SELECT jsonb_matches_named_schema('urn:example:email', '"nope"')"#;
+        assert!(Spi::get_one::<bool>(query).unwrap().unwrap());
+
+        Spi::run("SET pg_jsonschema.validate_formats = on").unwrap();
+        assert!(!Spi::get_one::<bool>(query).unwrap().unwrap());
+        assert_eq!(
+            Spi::get_one::<String>(r#"SELECT jsonschema_by_name('urn:example:email')::text"#)
+                .unwrap()
+                .unwrap(),
+            r#"{"$schema":"https://json-schema.org/draft/2020-12/schema","format":"email","x-pg-validate-formats":true}"#,
+        );
+    }
+
+    #[pg_test]
+    fn test_registry_update_invalidates_ref() {
+        Spi::run(
+            r#"INSERT INTO jsonschema.registry VALUES ('https://example.com/item.json', '{"type":"string"}')"#,
+        )
+        .unwrap();
+        let query = r#"
+            SELECT jsonb_matches_compiled_schema(
+                '{"items":{"$ref":"https://example.com/item.json"}}'::jsonschema,
+                '["a", "b"]'::jsonb
+            )
+        "#;
+        assert!(Spi::get_one::<bool>(query).unwrap().unwrap());
+
+        Spi::run(r#"DELETE FROM jsonschema.registry WHERE uri = 'https://example.com/item.json'"#)
+            .unwrap();
+        Spi::run(
+            r#"INSERT INTO jsonschema.registry VALUES ('https://example.com/item.json', '{"type":"integer"}')"#,
+        )
+        .unwrap();
+        assert!(!Spi::get_one::<bool>(query).unwrap().unwrap());
+    }
+
+    #[pg_test]
+    fn test_cache_size_guc_evicts() {
+        Spi::run("SELECT jsonschema_cache_reset()").unwrap();
+        Spi::run("SET pg_jsonschema.cache_size = 1").unwrap();
+        let result = Spi::get_one::<i64>(
+            r#"
+            SELECT count(*)
+            FROM generate_series(1, 10) i
+            WHERE jsonb_matches_compiled_schema(
+                jsonb_build_object('maximum', i)::jsonschema,
+                to_jsonb(5)
+            )
+            "#,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(result, 6);
+        let stats = Spi::get_three::<i32, i32, i64>(
+            "SELECT entries, capacity, evictions FROM jsonschema_cache_stats()",
+        )
+        .unwrap();
+        assert_eq!(stats, (Some(1), Some(1), Some(9)));
+        Spi::run("RESET pg_jsonschema.cache_size").unwrap();
+    }
+
+    #[pg_test]
+    fn test_cache_stats_and_reset() {
+        Spi::run("SELECT jsonschema_cache_reset()").unwrap();
+        Spi::run(
+            r#"
+            SELECT jsonb_matches_compiled_schema(s::jsonschema, '1')
+            FROM (VALUES ('{"minimum":0}'), ('{"maximum":9}'), ('{"minimum":0}')) v(s)
+            "#,
+        )
+        .unwrap();
+        let (entries, hits) =
+            Spi::get_two::<i32, i64>("SELECT entries, hits FROM jsonschema_cache_stats()").unwrap();
+        assert_eq!(entries, Some(2));
+        assert!(hits.unwrap() >= 1);
+
+        let listed = Spi::get_one::<i64>("SELECT count(*) FROM jsonschema_cache_entries()")
+            .unwrap()
+            .unwrap();
+        assert_eq!(listed, 2);
+
+        Spi::run("SELECT jsonschema_cache_reset()").unwrap();
+        let (entries, misses) =
+            Spi::get_two::<i32, i64>("SELECT entries, misses FROM jsonschema_cache_stats()")
+                .unwrap();
+        assert_eq!(entries, Some(0));
+        assert_eq!(misses, Some(0));
+    }
+
+    #[pg_test]
+    fn test_cache_stats_counts_evictions() {
+        Spi::run("SELECT jsonschema_cache_reset()").unwrap();
+        Spi::run("SET pg_jsonschema.cache_size = 2").unwrap();
+        Spi::run(
+            r#"
+            SELECT jsonb_build_object('maximum', i)::jsonschema
+            FROM generate_series(1, 5) i
+            "#,
+        )
+        .unwrap();
+        let (capacity, evictions) =
+            Spi::get_two::<i32, i64>("SELECT capacity, evictions FROM jsonschema_cache_stats()")
+                .unwrap();
+        assert_eq!(capacity, Some(2));
+        assert_eq!(evictions, Some(3));
+        Spi::run("RESET pg_jsonschema.cache_size").unwrap();
+    }
+
+    #[pg_test]
+    fn test_json_matches_schema_reuses_cached_validator() {
+        Spi::run("SELECT jsonschema_cache_reset()").unwrap();
+        let result = Spi::get_one::<i64>(
+            r#"
+            SELECT count(*)
+            FROM generate_series(1, 100) i
+            WHERE jsonb_matches_schema('{"type":"integer","minimum":0}', to_jsonb(i))
+            "#,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(result, 100);
+        let misses = Spi::get_one::<i64>("SELECT misses FROM jsonschema_cache_stats()")
+            .unwrap()
+            .unwrap();
+        assert_eq!(misses, 1);
+    }
+
+    #[pg_test]
+    fn test_json_matches_schema_per_row_schemas() {
+        let result = Spi::get_one::<i64>(
+            r#"
+            WITH data(s, v) AS (
+                VALUES
+                    ('{"type":"string"}'::json, '"hello"'::json),
+                    ('{"type":"integer"}'::json, '42'::json),
+                    ('{"type":"integer"}'::json, '"nope"'::json)
+            )
+            SELECT count(*) FROM data WHERE json_matches_schema(s, v)
+            "#,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(result, 2);
+    }
+
+    #[pg_test]
+    fn test_jsonschema_explicit_draft() {
+        // Draft 4 spells exclusive bounds as booleans next to `maximum`.
+        let schema = r#"'{"maximum": 5, "exclusiveMaximum": true}'"#;
+        let matches = Spi::get_one::<bool>(&format!(
+            "SELECT jsonb_matches_compiled_schema(jsonschema_from_jsonb({schema}, draft => '4'), '5')"
+        ))
+        .unwrap();
+        assert_eq!(matches, Some(false));
+
+        let text =
+            Spi::get_one::<String>(&format!("SELECT jsonschema_from_json({schema}, '4')::text"))
+                .unwrap()
+                .unwrap();
+        assert!(text.contains(r#""$schema":"http://json-schema.org/draft-04/schema#""#));
+    }
+
+    #[pg_test]
+    fn test_jsonschema_explicit_draft_overrides_schema() {
+        let text = Spi::get_one::<String>(
+            r#"
+            SELECT jsonschema_from_jsonb(
+                '{"$schema": "http://json-schema.org/draft-07/schema#"}', '2020-12'
+            )::text
+            "#,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            text,
+            r#"{"$schema":"https://json-schema.org/draft/2020-12/schema","x-pg-validate-formats":null}"#
+        );
+    }
+
+    #[pg_test]
+    #[should_panic(expected = "unknown JSON Schema draft \"5\"")]
+    fn test_jsonschema_unknown_draft() {
+        Spi::run("SELECT jsonschema_from_jsonb('{}', '5')").unwrap();
+    }
+
+    #[pg_test]
+    fn test_default_draft_guc() {
+        Spi::run("SET pg_jsonschema.default_draft = '6'").unwrap();
+        let defaulted = Spi::get_one::<String>(r#"SELECT '{"type": "integer"}'::jsonschema::text"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            defaulted,
+            r#"{"$schema":"http://json-schema.org/draft-06/schema#","type":"integer","x-pg-validate-formats":null}"#
+        );
+
+        // A declared `$schema` wins over the default.
+        let declared = Spi::get_one::<String>(
+            r#"SELECT '{"$schema": "http://json-schema.org/draft-07/schema#"}'::jsonschema::text"#,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            declared,
+            r#"{"$schema":"http://json-schema.org/draft-07/schema#","x-pg-validate-formats":null}"#
+        );
+    }
+
+    #[pg_test]
+    fn test_format_is_annotation_by_default() {
+        let matches = Spi::get_one::<bool>(
+            r#"SELECT jsonb_matches_compiled_schema('{"format": "email"}', '"nope"')"#,
+        )
+        .unwrap();
+        assert_eq!(matches, Some(true));
+    }
+
+    #[pg_test]
+    fn test_format_assertion_per_schema() {
+        let matches = Spi::get_one::<bool>(
+            r#"
+            SELECT jsonb_matches_compiled_schema(
+                '{"format": "email", "x-pg-validate-formats": true}', '"nope"'
+            )
+            "#,
+        )
+        .unwrap();
+        assert_eq!(matches, Some(false));
+    }
+
+    #[pg_test]
+    fn test_validate_formats_guc() {
+        Spi::run("SET pg_jsonschema.validate_formats = on").unwrap();
+        let text = Spi::get_one::<String>(r#"SELECT '{"format": "date-time"}'::jsonschema::text"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            text,
+            r#"{"$schema":"https://json-schema.org/draft/2020-12/schema","format":"date-time","x-pg-validate-formats":true}"#
+        );
+
+        let matches = call::<bool>(
+            "json_matches_schema",
+            Json(json!({"format": "date-time"})),
+            Json(json!("yesterday")),
+        );
+        assert!(!matches);
+
+        // Schemas opting out keep `format` as an annotation.
+        let matches = call::<bool>(
+            "json_matches_schema",
+            Json(json!({"format": "date-time", "x-pg-validate-formats": false})),
+            Json(json!("yesterday")),
+        );
+        assert!(matches);
+    }
+
+    #[pg_test]
+    fn test_validate_formats_survives_restore() {
+        let dumped =
+            Spi::get_one::<String>(r#"SELECT '{"format": "date-time"}'::jsonschema::text"#)
+                .unwrap()
+                .unwrap();
+
+        // Read back in under another setting, as a restore would.
+        Spi::run("SET pg_jsonschema.validate_formats = on").unwrap();
+        let restored = Spi::get_one_with_args::<String>(
+            "SELECT $1::jsonschema::text",
+            &[dumped.as_str().into()],
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(restored, dumped);
+        let matches = Spi::get_one_with_args::<bool>(
+            r#"SELECT jsonb_matches_compiled_schema($1::jsonschema, '"yesterday"')"#,
+            &[dumped.as_str().into()],
+        )
+        .unwrap();
+        assert_eq!(matches, Some(true));
+    }
+
+    #[pg_test]
+    #[should_panic(expected = "invalid JSON schema")]
+    fn test_regex_engine_guc() {
+        let schema = r#"'{"pattern": "^(?!admin)"}'::jsonschema"#;
+        let matches = Spi::get_one::<bool>(&format!(
+            r#"SELECT jsonb_matches_compiled_schema({schema}, '"root"')"#
+        ))
+        .unwrap();
+        assert_eq!(matches, Some(true));
+
+        // Look-around is not supported by the linear-time engine.
+        Spi::run("SET pg_jsonschema.regex_engine = 'regex'").unwrap();
+        Spi::run(&format!("SELECT {schema}")).unwrap();
+    }
+
+    #[pg_test]
+    fn test_regex_backtrack_limit_guc() {
+        let query = r#"
+            SELECT jsonb_matches_compiled_schema(
+                '{"pattern": "^(\\w+)\\1$"}'::jsonschema, '"abcabc"'
+            )
+        "#;
+        assert_eq!(Spi::get_one::<bool>(query).unwrap(), Some(true));
+
+        // The cached validator is dropped, and matches now give up.
+        Spi::run("SET pg_jsonschema.regex_backtrack_limit = 1").unwrap();
+        assert_eq!(Spi::get_one::<bool>(query).unwrap(), Some(false));
+    }
+
+    #[pg_test]
+    fn test_schema_resource_limits() {
+        let rejected = |schema: &str| {
+            Spi::run(&format!(
+                r#"
+                DO $$ BEGIN
+                    PERFORM '{schema}'::jsonschema;
+                    RAISE 'schema was accepted';
+                EXCEPTION WHEN program_limit_exceeded THEN
+                END $$
+                "#
+            ))
+            .unwrap();
+        };
+        let accepted = |schema: &str| Spi::run(&format!("SELECT '{schema}'::jsonschema")).unwrap();
+
+        Spi::run("SET pg_jsonschema.max_schema_bytes = 32").unwrap();
+        rejected(r#"{"type": "string", "minLength": 1, "maxLength": 8}"#);
+        accepted(r#"{"type": "string"}"#);
+        // The canonical form as given is what counts, 31 bytes, not the draft
+        // and format assertion recorded in it.
+        Spi::run(r#"SELECT '{"type": "string", "minLength": 1}'::jsonb::jsonschema"#).unwrap();
+        Spi::run("RESET pg_jsonschema.max_schema_bytes").unwrap();
+
+        Spi::run("SET pg_jsonschema.max_schema_depth = 2").unwrap();
+        rejected(r#"{"properties": {"a": {"type": "string"}}}"#);
+        accepted(r#"{"properties": {}}"#);
+        Spi::run("RESET pg_jsonschema.max_schema_depth").unwrap();
+
+        Spi::run(
+            r#"
+            INSERT INTO jsonschema.registry VALUES
+                ('https://example.com/a.json', '{"$ref": "https://example.com/b.json"}'),
+                ('https://example.com/b.json', '{"$ref": "c.json"}'),
+                ('https://example.com/c.json', '{"type": "string"}')
+            "#,
+        )
+        .unwrap();
+        // A cached validator doesn't exempt a new value from the limits.
+        accepted(r#"{"$ref": "https://example.com/a.json"}"#);
+        Spi::run("SET pg_jsonschema.max_ref_depth = 2").unwrap();
+        rejected(r#"{"$ref": "https://example.com/a.json"}"#);
+        accepted(r#"{"$ref": "https://example.com/b.json"}"#);
+        Spi::run("SET pg_jsonschema.max_ref_depth = 3").unwrap();
+        accepted(r#"{"$ref": "https://example.com/a.json"}"#);
+
+        // Stored values recompile without the limits in effect at the time.
+        Spi::run(
+            r#"CREATE TABLE limited AS SELECT '{"$ref": "https://example.com/a.json"}'::jsonschema s"#,
+        )
+        .unwrap();
+        Spi::run("SET pg_jsonschema.max_ref_depth = 1").unwrap();
+        Spi::run("SELECT jsonschema_cache_reset()").unwrap();
+        let matches =
+            Spi::get_one::<bool>(r#"SELECT jsonb_matches_compiled_schema(s, '"x"') FROM limited"#)
+                .unwrap();
+        assert_eq!(matches, Some(true));
+    }
+
+    #[pg_test]
+    fn test_registered_format() {
+        Spi::run(
+            r#"
+            CREATE FUNCTION is_sku(value text) RETURNS boolean
+                LANGUAGE sql IMMUTABLE AS $$ SELECT value ~ '^SKU-[0-9]{4}$' $$;
+            SELECT jsonschema_register_format('sku', 'is_sku(text)');
+            "#,
+        )
+        .unwrap();
+        let schema = Json(json!({"format": "sku", "x-pg-validate-formats": true}));
+        assert!(call::<bool>(
+            "json_matches_schema",
+            schema,
+            Json(json!("SKU-0042"))
+        ));
+        let schema = Json(json!({"format": "sku", "x-pg-validate-formats": true}));
+        assert!(!call::<bool>(
+            "json_matches_schema",
+            schema,
+            Json(json!("0042"))
+        ));
+    }
+
+    #[pg_test]
+    fn test_registered_format_replaced() {
+        Spi::run(
+            r#"
+            CREATE FUNCTION accept(value text) RETURNS boolean
+                LANGUAGE sql IMMUTABLE AS $$ SELECT true $$;
+            CREATE FUNCTION reject(value text) RETURNS boolean
+                LANGUAGE sql IMMUTABLE AS $$ SELECT false $$;
+            SELECT jsonschema_register_format('tenant-id', 'accept(text)');
+            "#,
+        )
+        .unwrap();
+        let query = r#"
+            SELECT jsonb_matches_compiled_schema(
+                '{"format": "tenant-id", "x-pg-validate-formats": true}', '"acme"'
+            )
+        "#;
+        assert_eq!(Spi::get_one::<bool>(query).unwrap(), Some(true));
+
+        Spi::run("SELECT jsonschema_register_format('tenant-id', 'reject(text)')").unwrap();
+        assert_eq!(Spi::get_one::<bool>(query).unwrap(), Some(false));
+    }
+
+    #[pg_test]
+    fn test_registered_format_null_rejects() {
+        Spi::run(
+            r#"
+            CREATE FUNCTION is_known(value text) RETURNS boolean
+                LANGUAGE sql IMMUTABLE AS $$ SELECT CASE WHEN value = 'acme' THEN true END $$;
+            SELECT jsonschema_register_format('tenant-id', 'is_known(text)');
+            "#,
+        )
+        .unwrap();
+        let matches = |instance: &str| {
+            Spi::get_one::<bool>(&format!(
+                r#"
+                SELECT jsonb_matches_compiled_schema(
+                    '{{"format": "tenant-id", "x-pg-validate-formats": true}}', '"{instance}"'
+                )
+                "#
+            ))
+            .unwrap()
+        };
+        assert_eq!(matches("acme"), Some(true));
+        assert_eq!(matches("other"), Some(false));
+    }
+
+    #[pg_test]
+    #[should_panic(expected = "must take text and return boolean")]
+    fn test_register_format_checks_signature() {
+        Spi::run("SELECT jsonschema_register_format('iban', 'length(text)')").unwrap();
+    }
+
+    #[pg_test]
+    #[should_panic(expected = "permission denied for function private_check")]
+    fn test_registered_format_requires_execute() {
+        Spi::run(
+            r#"
+            CREATE FUNCTION private_check(value text) RETURNS boolean
+                LANGUAGE sql IMMUTABLE AS $$ SELECT true $$;
+            REVOKE EXECUTE ON FUNCTION private_check(text) FROM PUBLIC;
+            SELECT jsonschema_register_format('private', 'private_check(text)');
+            CREATE ROLE jsonschema_unprivileged;
+            SET ROLE jsonschema_unprivileged;
+            "#,
+        )
+        .unwrap();
+        Spi::run(
+            r#"SELECT jsonb_matches_compiled_schema('{"format": "private", "x-pg-validate-formats": true}', '"x"')"#,
+        )
+        .unwrap();
+    }
+
+    #[pg_test]
+    fn test_register_keyword_rejects_reserved_names() {
+        Spi::run(
+            r#"
+            CREATE FUNCTION never(key jsonb, instance jsonb) RETURNS text
+                LANGUAGE sql IMMUTABLE AS $$ SELECT 'never valid' $$;
+            "#,
+        )
+        .unwrap();
+        for name in ["type", "$ref", "x-pg-references", "x-pg-validate-formats"] {
+            Spi::run(&format!(
+                r#"
+                DO $$ BEGIN
+                    PERFORM jsonschema_register_keyword('{name}', 'never(jsonb, jsonb)');
+                    RAISE 'keyword name {name} was accepted';
+                EXCEPTION WHEN raise_exception THEN
+                    IF SQLERRM NOT LIKE '%is reserved' THEN
+                        RAISE;
+                    END IF;
+                END $$
+                "#
+            ))
+            .unwrap();
+        }
+    }
+
+    #[pg_test]
+    fn test_registered_keyword() {
+        Spi::run(
+            r#"
+            CREATE FUNCTION unique_by(key jsonb, instance jsonb) RETURNS text
+                LANGUAGE sql IMMUTABLE AS $$
+                    SELECT format('items are not unique by %s', key)
+                    WHERE (SELECT count(DISTINCT e -> (key ->> 0)) <> count(*)
+                           FROM jsonb_array_elements(instance) e)
+                $$;
+            SELECT jsonschema_register_keyword('x-unique-by', 'unique_by(jsonb, jsonb)');
+            "#,
+        )
+        .unwrap();
+        let schema = || Json(json!({"type": "array", "x-unique-by": "id"}));
+        assert!(call::<bool>(
+            "json_matches_schema",
+            schema(),
+            Json(json!([{"id": 1}, {"id": 2}]))
+        ));
+
+        let errors = call::<Vec<String>>(
+            "jsonschema_validation_errors",
+            schema(),
+            Json(json!([{"id": 1}, {"id": 1}])),
+        );
+        assert_eq!(errors, [r#"items are not unique by "id""#]);
+    }
+
+    #[pg_test]
+    #[should_panic(expected = "must take (jsonb, jsonb) and return text")]
+    fn test_register_keyword_checks_signature() {
+        Spi::run("SELECT jsonschema_register_keyword('x-nope', 'jsonb_typeof(jsonb)')").unwrap();
+    }
+
+    #[pg_test]
+    fn test_references_keyword() {
+        Spi::run(
+            r#"
+            CREATE TABLE customers (id int PRIMARY KEY);
+            INSERT INTO customers VALUES (1), (2);
+            "#,
+        )
+        .unwrap();
+        let schema = || {
+            Json(json!({
+                "properties": {
+                    "customer_id": {"x-pg-references": {"table": "customers", "column": "id"}}
+                }
+            }))
+        };
+        assert!(call::<bool>(
+            "json_matches_schema",
+            schema(),
+            Json(json!({"customer_id": 2}))
+        ));
+
+        let errors = call::<Vec<String>>(
+            "jsonschema_validation_errors",
+            schema(),
+            Json(json!({"customer_id": 3})),
+        );
+        assert_eq!(errors, ["3 is not present in customers(id)"]);
+    }
+
+    #[pg_test]
+    fn test_references_keyword_ignores_typmod() {
+        Spi::run(
+            r#"
+            CREATE TABLE codes (code varchar(3), amount numeric(5, 0));
+            INSERT INTO codes VALUES ('abc', 1);
+            "#,
+        )
+        .unwrap();
+        let matches = |column: &str, instance: &str| {
+            Spi::get_one::<bool>(&format!(
+                r#"SELECT jsonb_matches_compiled_schema(
+                    '{{"x-pg-references": {{"table": "codes", "column": "{column}"}}}}'::jsonschema,
+                    '{instance}'
+                )"#
+            ))
+            .unwrap()
+            .unwrap()
+        };
+        assert!(matches("code", r#""abc""#));
+        assert!(!matches("code", r#""abcdef""#));
+        assert!(matches("amount", "1"));
+        assert!(!matches("amount", "1.4"));
+    }
+
+    #[pg_test]
+    fn test_references_keyword_ignores_search_path() {
+        Spi::run(
+            r#"
+            CREATE TABLE customers (id int PRIMARY KEY);
+            INSERT INTO customers VALUES (1);
+            "#,
+        )
+        .unwrap();
+        let query = r#"
+            SELECT jsonb_matches_compiled_schema(
+                '{"x-pg-references": {"table": "customers", "column": "id"}}'::jsonschema, '2'
+            )
+        "#;
+        assert_eq!(Spi::get_one::<bool>(query).unwrap(), Some(false));
+
+        // A temporary table shadowing the referenced one is not consulted.
+        Spi::run(
+            r#"
+            CREATE TEMP TABLE customers (id int);
+            INSERT INTO customers VALUES (2);
+            "#,
+        )
+        .unwrap();
+        assert_eq!(Spi::get_one::<bool>(query).unwrap(), Some(false));
     }
-}
 
-#[pg_extern(immutable, strict, parallel_safe)]
-fn jsonschema_validation_errors(schema: Json, instance: Json) -> Vec<String> {
-    let validator = match jsonschema::validator_for(&schema.0) {
-        Ok(v) => v,
-        Err(err) => return vec![err.to_string()],
-    };
-    validator
-        .iter_errors(&instance.0)
-        .map(|err| err.to_string())
-        .collect()
-}
+    #[pg_test]
+    fn test_references_keyword_unknown_column() {
+        Spi::run("CREATE TABLE customers (id int PRIMARY KEY)").unwrap();
+        let errors = call::<Vec<String>>(
+            "jsonschema_validation_errors",
+            Json(json!({"x-pg-references": {"table": "customers", "column": "uuid"}})),
+            Json(json!(1)),
+        );
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains(r#"column "uuid" of table "customers" does not exist"#));
 
-#[pg_extern(immutable, strict, parallel_safe)]
-fn jsonschema_from_json(schema: pgrx::Json) -> JsonSchema {
-    JsonSchema::compile(schema.0)
-}
+        let errors = call::<Vec<String>>(
+            "jsonschema_validation_errors",
+            Json(json!({"x-pg-references": {"table": "clients", "column": "id"}})),
+            Json(json!(1)),
+        );
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains(r#"column "id" of table "clients" does not exist"#));
+    }
 
-#[pg_extern(immutable, strict, parallel_safe)]
-fn jsonschema_from_jsonb(schema: pgrx::JsonB) -> JsonSchema {
-    JsonSchema::compile(schema.0)
-}
+    #[pg_test]
+    fn test_references_keyword_uncastable_value() {
+        Spi::run(
+            r#"
+            CREATE TABLE customers (id int PRIMARY KEY);
+            INSERT INTO customers VALUES (1);
+            "#,
+        )
+        .unwrap();
+        let schema = || {
+            Json(json!({
+                "type": "integer",
+                "x-pg-references": {"table": "customers", "column": "id"}
+            }))
+        };
+        // Out of range for int4, and not an integer at all.
+        assert!(!call::<bool>(
+            "json_matches_schema",
+            schema(),
+            Json(json!(9999999999_i64))
+        ));
+        let errors =
+            call::<Vec<String>>("jsonschema_validation_errors", schema(), Json(json!("abc")));
+        assert_eq!(
+            errors,
+            [
+                r#""abc" is not of type "integer""#,
+                r#""abc" is not present in customers(id)"#
+            ]
+        );
+        // The lookup's subtransaction leaves the statement usable.
+        assert!(call::<bool>(
+            "json_matches_schema",
+            schema(),
+            Json(json!(1))
+        ));
+    }
 
-pgrx::extension_sql!(
-    r#"
-    CREATE CAST (json AS jsonschema)
-        WITH FUNCTION jsonschema_from_json(json);
+    #[pg_test]
+    fn test_jsonschema_send() {
+        let bytes =
+            Spi::get_one::<Vec<u8>>(r#"SELECT jsonschema_send('{"type": "integer"}'::jsonschema)"#)
+                .unwrap()
+                .unwrap();
+        assert_eq!(
+            bytes,
+            b"\x01{\"$schema\":\"https://json-schema.org/draft/2020-12/schema\",\"type\":\"integer\",\"x-pg-validate-formats\":null}"
+        );
+    }
 
-    CREATE CAST (jsonb AS jsonschema)
-        WITH FUNCTION jsonschema_from_jsonb(jsonb);
-    "#,
-    name = "jsonschema_casts",
-    requires = [jsonschema_from_json, jsonschema_from_jsonb],
-);
+    #[pg_test]
+    fn test_jsonschema_binary_copy_roundtrip() {
+        Spi::run(
+            r#"
+            CREATE TABLE schemas (s jsonschema);
+            INSERT INTO schemas VALUES ('{"type": "integer"}'), ('{"maxLength": 3}');
+            DO $$
+            DECLARE
+                path text := current_setting('data_directory') || '/jsonschema_binary_copy.bin';
+            BEGIN
+                EXECUTE format('COPY schemas TO %L (FORMAT binary)', path);
+                -- Settings of the receiving session must not change the values.
+                SET LOCAL pg_jsonschema.default_draft = '7';
+                SET LOCAL pg_jsonschema.validate_formats = on;
+                EXECUTE format('COPY schemas FROM %L (FORMAT binary)', path);
+            END
+            $$;
+            "#,
+        )
+        .unwrap();
+        let (rows, distinct) =
+            Spi::get_two::<i64, i64>("SELECT count(*), count(DISTINCT s::text) FROM schemas")
+                .unwrap();
+        assert_eq!(rows, Some(4));
+        assert_eq!(distinct, Some(2));
+    }
 
-#[pg_extern(immutable, strict, parallel_safe)]
-fn json_matches_compiled_schema(
-    schema: JsonSchema,
-    instance: Json,
-    fcinfo: pg_sys::FunctionCallInfo,
-) -> bool {
-    let validator = unsafe { fn_extra_get_or_compile(&schema, fcinfo) };
-    validator.is_valid(&instance.0)
-}
+    #[pg_test]
+    fn test_jsonschema_storage_layout() {
+        // 4 byte varlena header, 1 byte version and 16 byte fingerprint.
+        let size = Spi::get_one::<i32>(
+            r#"SELECT pg_column_size(s) - octet_length(s::text) FROM (SELECT '{"type": "integer"}'::jsonschema s) t"#,
+        )
+        .unwrap();
+        assert_eq!(size, Some(4 + 1 + 16));
+    }
 
-#[pg_extern(immutable, strict, parallel_safe)]
-fn jsonb_matches_compiled_schema(
-    schema: JsonSchema,
-    instance: pgrx::JsonB,
-    fcinfo: pg_sys::FunctionCallInfo,
-) -> bool {
-    let validator = unsafe { fn_extra_get_or_compile(&schema, fcinfo) };
-    validator.is_valid(&instance.0)
-}
+    #[pg_test]
+    fn test_legacy_value_equals_new_value() {
+        #[derive(serde::Serialize)]
+        struct Legacy {
+            value: &'static str,
+        }
+        // As stored by 0.3.4: CBOR, and no recorded compile options.
+        let legacy = unsafe {
+            let varlena = pgrx::datum::cbor_encode(Legacy {
+                value: r#"{"type":"integer"}"#,
+            });
+            crate::compiled::JsonSchema::from_datum(pg_sys::Datum::from(varlena), false).unwrap()
+        };
+        let new = Spi::get_one::<crate::compiled::JsonSchema>(
+            r#"SELECT '{"type":"integer"}'::jsonschema"#,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(legacy.value, new.value);
+        assert_eq!(legacy.fingerprint(), new.fingerprint());
+    }
 
-#[pg_extern(immutable, strict, parallel_safe)]
-fn json_validation_errors_compiled(
-    schema: JsonSchema,
-    instance: Json,
-    fcinfo: pg_sys::FunctionCallInfo,
-) -> Vec<String> {
-    let validator = unsafe { fn_extra_get_or_compile(&schema, fcinfo) };
-    validator
-        .iter_errors(&instance.0)
-        .map(|err| err.to_string())
-        .collect()
-}
+    #[pg_test]
+    fn test_jsonschema_toasted_equality_and_hashing() {
+        Spi::run(
+            r#"
+            CREATE TABLE schemas (s jsonschema);
+            INSERT INTO schemas
+            SELECT jsonb_build_object('enum', (SELECT jsonb_agg(i) FROM generate_series(1, 20000) i))
+            FROM generate_series(1, 2)
+            UNION ALL
+            SELECT '{"type": "string"}';
+            "#,
+        )
+        .unwrap();
+        let groups =
+            Spi::get_one::<i64>("SELECT count(*) FROM (SELECT s FROM schemas GROUP BY s) t")
+                .unwrap();
+        assert_eq!(groups, Some(2));
 
-#[pg_extern(immutable, strict, parallel_safe)]
-fn jsonb_validation_errors_compiled(
-    schema: JsonSchema,
-    instance: pgrx::JsonB,
-    fcinfo: pg_sys::FunctionCallInfo,
-) -> Vec<String> {
-    let validator = unsafe { fn_extra_get_or_compile(&schema, fcinfo) };
-    validator
-        .iter_errors(&instance.0)
-        .map(|err| err.to_string())
-        .collect()
-}
+        Spi::run("SET enable_mergejoin = off; SET enable_nestloop = off").unwrap();
+        let pairs =
+            Spi::get_one::<i64>("SELECT count(*) FROM schemas a JOIN schemas b ON a.s = b.s")
+                .unwrap();
+        assert_eq!(pairs, Some(5));
+    }
 
-#[pg_schema]
-#[cfg(any(test, feature = "pg_test"))]
-mod tests {
-    use pgrx::*;
-    use serde_json::json;
+    #[pg_test]
+    fn test_jsonschema_fingerprint() {
+        let matches = Spi::get_one::<bool>(
+            r#"
+            SELECT jsonschema_fingerprint(s) = substring(sha256(convert_to(s::text, 'UTF8')) for 16)
+            FROM (SELECT '{"type": "integer"}'::jsonschema s) t
+            "#,
+        )
+        .unwrap();
+        assert_eq!(matches, Some(true));
 
-    macro_rules! compiled_schema_tests {
-        (json { $($name:ident: $schema:literal, $instance:literal => $expected:literal)* }) => {$(
-            #[pg_test]
-            fn $name() {
-                let result = Spi::get_one::<bool>(concat!(
-                    "SELECT json_matches_compiled_schema('", $schema, "'::jsonschema, '", $instance, "'::json)"
-                )).unwrap().unwrap();
-                assert_eq!(result, $expected);
-            }
-        )*};
-        (jsonb { $($name:ident: $schema:literal, $instance:literal => $expected:literal)* }) => {$(
-            #[pg_test]
-            fn $name() {
-                let result = Spi::get_one::<bool>(concat!(
-                    "SELECT jsonb_matches_compiled_schema('", $schema, "'::jsonschema, '", $instance, "'::jsonb)"
-                )).unwrap().unwrap();
-                assert_eq!(result, $expected);
-            }
-        )*};
-        (errors_json { $($name:ident: $schema:literal, $instance:literal => [$($err:literal),*])* }) => {$(
-            #[pg_test]
-            fn $name() {
-                let errors = Spi::get_one::<Vec<String>>(concat!(
-                    "SELECT json_validation_errors_compiled('", $schema, "'::jsonschema, '",
-                    $instance, "'::json)"
-                )).unwrap().unwrap();
-                assert_eq!(errors, [$($err),*]);
-            }
-        )*};
-        (errors_jsonb { $($name:ident: $schema:literal, $instance:literal => [$($err:literal),*])* }) => {$(
-            #[pg_test]
-            fn $name() {
-                let errors = Spi::get_one::<Vec<String>>(concat!(
-                    "SELECT jsonb_validation_errors_compiled('", $schema, "'::jsonschema, '",
-                    $instance, "'::jsonb)"
-                )).unwrap().unwrap();
-                assert_eq!(errors, [$($err),*]);
-            }
-        )*};
+        // Equivalent schemas share a fingerprint.
+        let same = Spi::get_one::<bool>(
+            r#"
+            SELECT jsonschema_fingerprint('{"minimum": 1, "type": "integer"}')
+                 = jsonschema_fingerprint('{"type": "integer", "minimum": 1}')
+            "#,
+        )
+        .unwrap();
+        assert_eq!(same, Some(true));
     }
 
-    compiled_schema_tests!(json {
-        test_json_matches_compiled_schema: r#"{"type":"string"}"#, r#""hello""# => true
-        test_json_rejects_compiled_schema: r#"{"type":"string"}"#, r#"42"#      => false
-    });
+    #[pg_test]
+    fn test_cache_entries_keyed_by_fingerprint() {
+        Spi::run("SELECT jsonschema_cache_reset()").unwrap();
+        let hits = Spi::get_one::<i64>(
+            r#"
+            WITH s AS (SELECT '{"maxLength": 5}'::jsonschema AS s)
+            SELECT e.hits
+            FROM s, LATERAL (SELECT json_matches_compiled_schema(s, '"abc"')) v,
+                 jsonschema_cache_entries() e
+            WHERE e.fingerprint = jsonschema_fingerprint(s)
+            "#,
+        )
+        .unwrap();
+        assert!(hits.is_some());
+    }
 
-    compiled_schema_tests!(jsonb {
-        test_jsonb_matches_compiled_schema: r#"{"type":"string"}"#, r#""hello""# => true
-        test_jsonb_rejects_compiled_schema: r#"{"type":"string"}"#, r#"42"#      => false
-    });
+    #[pg_test]
+    fn test_match_operators() {
+        let schema = r#"'{"type": "integer"}'::jsonschema"#;
+        let row = |query: &str| Spi::get_one::<bool>(&query.replace("$schema", schema)).unwrap();
+        assert_eq!(row("SELECT '1'::jsonb @~ $schema"), Some(true));
+        assert_eq!(row(r#"SELECT '"1"'::jsonb @~ $schema"#), Some(false));
+        assert_eq!(row(r#"SELECT '"1"'::jsonb !@~ $schema"#), Some(true));
+        assert_eq!(row("SELECT $schema ~@ '1'::jsonb"), Some(true));
+        assert_eq!(row("SELECT $schema !~@ '1'::jsonb"), Some(false));
+        assert_eq!(row("SELECT '1'::json @~ $schema"), Some(true));
+        assert_eq!(row("SELECT '1.5'::json !@~ $schema"), Some(true));
+        assert_eq!(row("SELECT $schema ~@ '[]'::json"), Some(false));
+        assert_eq!(row("SELECT NOT ('1'::jsonb @~ $schema)"), Some(false));
+    }
 
-    compiled_schema_tests!(errors_json {
-        test_validation_errors_compiled_with_error:
-            r#"{"maxLength":4}"#, r#""toolong""#
-            => [r#""toolong" is longer than 4 characters"#]
-    });
+    #[pg_test]
+    #[should_panic(expected = "violates check constraint")]
+    fn test_match_operator_check_constraint() {
+        Spi::run(
+            r#"
+            CREATE TABLE customers (
+                info jsonb CHECK (info @~ '{"required": ["name"]}'::jsonschema)
+            );
+            INSERT INTO customers VALUES ('{"name": "Alice"}');
+            INSERT INTO customers VALUES ('{}');
+            "#,
+        )
+        .unwrap();
+    }
 
-    compiled_schema_tests!(errors_jsonb {
-        test_validation_errors_compiled_jsonb:
-            r#"{"type":"string"}"#, r#"42"#
-            => ["42 is not of type \"string\""]
-        test_validation_errors_compiled_jsonb_object:
-            r#"{"type":"object","required":["name"],"properties":{"name":{"type":"string"}}}"#,
-            r#"{"name":42}"#
-            => ["42 is not of type \"string\""]
-    });
+    #[pg_test]
+    fn test_match_selectivity_from_statistics() {
+        Spi::run(
+            r#"
+            CREATE TABLE docs (doc jsonb);
+            INSERT INTO docs SELECT '{"a": 1}' FROM generate_series(1, 900);
+            INSERT INTO docs SELECT '{"a": "x"}' FROM generate_series(1, 100);
+            ANALYZE docs;
+            "#,
+        )
+        .unwrap();
+        let schema = r#"'{"properties": {"a": {"type": "integer"}}}'::jsonschema"#;
+        let estimate = |predicate: &str| {
+            let plan = Spi::get_one::<pgrx::Json>(&format!(
+                "EXPLAIN (FORMAT JSON) SELECT * FROM docs WHERE {}",
+                predicate.replace("$schema", schema)
+            ))
+            .unwrap()
+            .unwrap();
+            plan.0[0]["Plan"]["Plan Rows"].as_f64().unwrap()
+        };
+        assert_eq!(estimate("doc @~ $schema"), 900.0);
+        assert_eq!(estimate("$schema !~@ doc"), 100.0);
+        assert_eq!(
+            estimate("jsonb_matches_compiled_schema($schema, doc)"),
+            900.0
+        );
+        assert_eq!(
+            estimate("jsonb_not_matches_jsonschema(doc, $schema)"),
+            100.0
+        );
+    }
+
+    #[pg_test]
+    fn test_jsonb_instance_decoding() {
+        for doc in [
+            "null",
+            "true",
+            r#""é\"x""#,
+            "1.50",
+            "123456789012345678901234567890",
+            "[]",
+            "{}",
+            r#"{"a": [1, {"b": null, "cc": [false, "x"]}], "": -0.0, "long key": {}}"#,
+        ] {
+            let roundtrip = Spi::get_one::<bool>(&format!(
+                "SELECT jsonb_assert_matches_schema('true'::jsonschema, doc)::text = doc::text \
+                 FROM (SELECT $${doc}$$::jsonb AS doc) t"
+            ))
+            .unwrap();
+            assert_eq!(roundtrip, Some(true), "{doc}");
+        }
+        let matches = Spi::get_one::<bool>(
+            r#"SELECT jsonb_matches_compiled_schema(
+                '{"properties": {"a": {"type": "array", "items": {"type": "integer"}}}}'::jsonschema,
+                '{"a": [1, 2, 3]}'
+            )"#,
+        )
+        .unwrap();
+        assert_eq!(matches, Some(true));
+    }
 
     #[pg_test]
     fn test_jsonschema_cast_from_json() {
@@ -203,7 +1995,10 @@ mod tests {
         let result = Spi::get_one::<String>(r#"SELECT '{"b":1,"a":2}'::jsonschema::text"#)
             .unwrap()
             .unwrap();
-        assert_eq!(result, r#"{"a":2,"b":1}"#);
+        assert_eq!(
+            result,
+            r#"{"$schema":"https://json-schema.org/draft/2020-12/schema","a":2,"b":1,"x-pg-validate-formats":null}"#
+        );
     }
 
     #[pg_test]
@@ -378,19 +2173,45 @@ mod tests {
         Spi::run("SELECT 'not valid json'::jsonschema").unwrap();
     }
 
+    /// Calls a two-argument SQL function with Rust-built arguments.
+    fn call<T: FromDatum + IntoDatum>(function: &str, schema: Json, instance: impl IntoDatum) -> T {
+        Spi::get_one_with_args::<T>(
+            &format!("SELECT {function}($1, $2)"),
+            &[schema.into(), instance.into()],
+        )
+        .unwrap()
+        .unwrap()
+    }
+
     #[pg_test]
     fn test_jsonschema_validation_errors_invalid_schema() {
-        let errors = crate::jsonschema_validation_errors(
+        let errors = call::<Vec<String>>(
+            "jsonschema_validation_errors",
             Json(json!({ "enum": 1 })),
             Json(json!("anything")),
         );
         assert!(!errors.is_empty());
     }
 
+    #[pg_test]
+    fn test_jsonschema_validation_error_details() {
+        let errors = call::<Vec<JsonB>>(
+            "jsonschema_validation_error_details",
+            Json(json!({ "maxLength": 4 })),
+            Json(json!("123456789")),
+        );
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0["keyword"], "maxLength");
+        assert_eq!(errors[0].0["instance_path"], "");
+        assert_eq!(errors[0].0["schema_path"], "/maxLength");
+        assert_eq!(errors[0].0["instance"], "123456789");
+    }
+
     #[pg_test]
     fn test_json_matches_schema_rs() {
         let max_length: i32 = 5;
-        assert!(crate::json_matches_schema(
+        assert!(call::<bool>(
+            "json_matches_schema",
             Json(json!({ "maxLength": max_length })),
             Json(json!("foo")),
         ));
@@ -399,7 +2220,8 @@ mod tests {
     #[pg_test]
     fn test_json_not_matches_schema_rs() {
         let max_length: i32 = 5;
-        assert!(!crate::json_matches_schema(
+        assert!(!call::<bool>(
+            "json_matches_schema",
             Json(json!({ "maxLength": max_length })),
             Json(json!("foobar")),
         ));
@@ -407,15 +2229,18 @@ mod tests {
 
     #[pg_test]
     fn test_json_matches_schema_arbitrary_precision() {
-        assert!(crate::json_matches_schema(
+        assert!(call::<bool>(
+            "json_matches_schema",
             Json(json!({ "type": "number", "multipleOf": 0.1 })),
             Json(json!(17.2)),
         ));
-        assert!(crate::json_matches_schema(
+        assert!(call::<bool>(
+            "json_matches_schema",
             Json(json!({ "type": "number", "multipleOf": 0.2 })),
             Json(json!(17.2)),
         ));
-        assert!(!crate::json_matches_schema(
+        assert!(!call::<bool>(
+            "json_matches_schema",
             Json(json!({ "type": "number", "multipleOf": 0.3 })),
             Json(json!(17.2)),
         ));
@@ -424,7 +2249,8 @@ mod tests {
     #[pg_test]
     fn test_jsonb_matches_schema_rs() {
         let max_length: i32 = 5;
-        assert!(crate::jsonb_matches_schema(
+        assert!(call::<bool>(
+            "jsonb_matches_schema",
             Json(json!({ "maxLength": max_length })),
             JsonB(json!("foo")),
         ));
@@ -433,7 +2259,8 @@ mod tests {
     #[pg_test]
     fn test_jsonb_not_matches_schema_rs() {
         let max_length: i32 = 5;
-        assert!(!crate::jsonb_matches_schema(
+        assert!(!call::<bool>(
+            "jsonb_matches_schema",
             Json(json!({ "maxLength": max_length })),
             JsonB(json!("foobar")),
         ));
@@ -510,7 +2337,8 @@ mod tests {
 
     #[pg_test]
     fn test_jsonschema_validation_errors_none() {
-        let errors = crate::jsonschema_validation_errors(
+        let errors = call::<Vec<String>>(
+            "jsonschema_validation_errors",
             Json(json!({ "maxLength": 4 })),
             Json(json!("foo")),
         );
@@ -519,7 +2347,8 @@ mod tests {
 
     #[pg_test]
     fn test_jsonschema_validation_erros_one() {
-        let errors = crate::jsonschema_validation_errors(
+        let errors = call::<Vec<String>>(
+            "jsonschema_validation_errors",
             Json(json!({ "maxLength": 4 })),
             Json(json!("123456789")),
         );
@@ -529,7 +2358,8 @@ mod tests {
 
     #[pg_test]
     fn test_jsonschema_validation_errors_multiple() {
-        let errors = crate::jsonschema_validation_errors(
+        let errors = call::<Vec<String>>(
+            "jsonschema_validation_errors",
             Json(json!(
             {
                 "type": "object",
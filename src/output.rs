@@ -0,0 +1,41 @@
+//! JSON Schema output formats (JSON Schema 2020-12 Core, Section 12.4).
+use serde_json::Value;
+
+/// Evaluates `instance` and renders the result in the named output format.
+///
+/// `flag`, `basic` and `verbose` map directly onto the crate's flag, list and
+/// hierarchical outputs. `detailed` is the hierarchical output with units that
+/// carry neither errors nor annotations pruned away.
+pub(crate) fn evaluate(validator: &jsonschema::Validator, instance: &Value, format: &str) -> Value {
+    let evaluation = validator.evaluate(instance);
+    let output = match format {
+        "flag" => serde_json::to_value(evaluation.flag()),
+        "basic" => serde_json::to_value(evaluation.list()),
+        "detailed" => serde_json::to_value(evaluation.hierarchical()).map(|mut root| {
+            prune_details(&mut root);
+            root
+        }),
+        "verbose" => serde_json::to_value(evaluation.hierarchical()),
+        _ => pgrx::error!(
+            "unknown output format \"{format}\", expected one of: flag, basic, detailed, verbose"
+        ),
+    };
+    output.unwrap_or_else(|err| pgrx::error!("failed to serialize evaluation output: {err}"))
+}
+
+/// Recursively drops `details` entries that carry no errors or annotations.
+/// Returns whether `unit` itself still carries any.
+fn prune_details(unit: &mut Value) -> bool {
+    let Some(obj) = unit.as_object_mut() else {
+        return false;
+    };
+    if let Some(Value::Array(details)) = obj.get_mut("details") {
+        details.retain_mut(prune_details);
+        if details.is_empty() {
+            obj.remove("details");
+        }
+    }
+    ["errors", "annotations", "droppedAnnotations", "details"]
+        .iter()
+        .any(|key| obj.contains_key(*key))
+}
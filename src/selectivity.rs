@@ -0,0 +1,82 @@
+//! Selectivity estimation for schema-match predicates.
+//!
+//! The match operators use PostgreSQL's `matchingsel`, which validates the
+//! column's most common values and histogram entries against the schema and
+//! weights the results by their frequencies. The functions behind the operators
+//! get the same estimate from a planner support function, so a predicate plans
+//! alike whichever form it is written in.
+use std::{cell::RefCell, collections::HashMap};
+
+use pgrx::{datum::Internal, *};
+
+/// Estimate for columns without statistics; the one `matchingsel` uses.
+const DEFAULT_MATCH_SEL: f64 = 0.010;
+
+thread_local! {
+    /// Operators implemented by the match functions, by function OID.
+    static OPERATORS: RefCell<HashMap<pg_sys::Oid, pg_sys::Oid>> = RefCell::new(HashMap::new());
+}
+
+/// Planner support function of the match functions. Only restriction
+/// selectivity is estimated; other requests are left to the planner.
+#[pg_extern(immutable, strict, parallel_safe)]
+fn jsonschema_match_support(request: Internal) -> Internal {
+    let not_handled = Internal::from(Some(pg_sys::Datum::from(0usize)));
+    let Some(datum) = request.unwrap() else {
+        return not_handled;
+    };
+    unsafe {
+        let node = datum.cast_mut_ptr::<pg_sys::Node>();
+        if (*node).type_ != pg_sys::NodeTag::T_SupportRequestSelectivity {
+            return not_handled;
+        }
+        let request = node.cast::<pg_sys::SupportRequestSelectivity>();
+        if (*request).is_join {
+            return not_handled;
+        }
+        let Some(operator) = operator_of((*request).funcid) else {
+            return not_handled;
+        };
+        // Only the operator's function is used, to evaluate the predicate on
+        // the sampled values, so the estimate is the function's own.
+        (*request).selectivity = pg_sys::generic_restriction_selectivity(
+            (*request).root,
+            operator,
+            (*request).inputcollid,
+            (*request).args,
+            (*request).varRelid,
+            DEFAULT_MATCH_SEL,
+        );
+    }
+    Internal::from(Some(datum))
+}
+
+/// The operator `function` implements, if any.
+fn operator_of(function: pg_sys::Oid) -> Option<pg_sys::Oid> {
+    if let Some(operator) = OPERATORS.with_borrow(|operators| operators.get(&function).copied()) {
+        return Some(operator);
+    }
+    let operator = Spi::get_one_with_args::<pg_sys::Oid>(
+        "SELECT oid FROM pg_catalog.pg_operator WHERE oprcode::oid = $1",
+        &[function.into()],
+    )
+    .ok()
+    .flatten()?;
+    OPERATORS.with_borrow_mut(|operators| operators.insert(function, operator));
+    Some(operator)
+}
+
+pgrx::extension_sql!(
+    r#"
+    ALTER FUNCTION json_matches_compiled_schema(jsonschema, json) SUPPORT jsonschema_match_support;
+    ALTER FUNCTION jsonb_matches_compiled_schema(jsonschema, jsonb) SUPPORT jsonschema_match_support;
+    ALTER FUNCTION json_not_matches_compiled_schema(jsonschema, json) SUPPORT jsonschema_match_support;
+    ALTER FUNCTION jsonb_not_matches_compiled_schema(jsonschema, jsonb) SUPPORT jsonschema_match_support;
+    ALTER FUNCTION json_matches_jsonschema(json, jsonschema) SUPPORT jsonschema_match_support;
+    ALTER FUNCTION jsonb_matches_jsonschema(jsonb, jsonschema) SUPPORT jsonschema_match_support;
+    ALTER FUNCTION json_not_matches_jsonschema(json, jsonschema) SUPPORT jsonschema_match_support;
+    ALTER FUNCTION jsonb_not_matches_jsonschema(jsonb, jsonschema) SUPPORT jsonschema_match_support;
+    "#,
+    name = "jsonschema_match_selectivity",
+    requires = ["jsonschema_operators", jsonschema_match_support],
+);